@@ -0,0 +1,17 @@
+#import bevy_sprite_instancing::instanced_sprite
+
+struct PulseMaterial {
+    color: vec4<f32>,
+};
+
+@group(2) @binding(0)
+var<uniform> material: PulseMaterial;
+
+@fragment
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = instanced_sprite_color(in);
+    let pulse = 0.5 + 0.5 * sin(globals.time * 4.0);
+    let tint = mix(color.rgb, material.color.rgb, pulse * material.color.a);
+
+    return instanced_sprite_blend(vec4(tint, color.a));
+}
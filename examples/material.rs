@@ -0,0 +1,67 @@
+use bevy::{
+    prelude::*,
+    reflect::TypeUuid,
+    render::render_resource::{AsBindGroup, ShaderRef},
+};
+use bevy_sprite_instancing::{
+    InstancedSprite, InstancedSpriteMaterial, InstancedSpriteMaterialPlugin,
+    InstancedSpriteRenderPlugin, InstancedSpritesheet, SpriteInstancingGroup,
+};
+
+pub const ENTITY_COUNT: usize = 10000;
+
+#[derive(AsBindGroup, TypeUuid, Clone)]
+#[uuid = "0d4e9c16-5b4f-4c56-a8c0-5f3f4a3d8b71"]
+struct PulseMaterial {
+    #[uniform(0)]
+    color: Color,
+}
+
+impl InstancedSpriteMaterial for PulseMaterial {
+    fn fragment_shader() -> ShaderRef {
+        "pulse_material.wgsl".into()
+    }
+}
+
+fn setup(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut materials: ResMut<Assets<PulseMaterial>>,
+) {
+    commands.spawn(Camera2dBundle::default());
+
+    let spritesheet = InstancedSpritesheet {
+        image: asset_server.load("map0.png"),
+        width_tiles: 32,
+        height_tiles: 32,
+        ..default()
+    };
+    let material = materials.add(PulseMaterial { color: Color::RED });
+
+    let group_id = commands
+        .spawn((SpriteInstancingGroup::default(), spritesheet, material))
+        .id();
+
+    for _ in 0..ENTITY_COUNT {
+        let x = (rand::random::<f32>() - 0.5) * 2000.0;
+        let y = (rand::random::<f32>() - 0.5) * 1000.0;
+        let transform = Transform::from_xyz(x, y, 0.0).with_scale(Vec3::new(16.0, 16.0, 1.0));
+
+        commands.spawn((
+            TransformBundle::from_transform(transform),
+            InstancedSprite {
+                group_id,
+                ..default()
+            },
+        ));
+    }
+}
+
+pub fn main() {
+    App::new()
+        .add_plugins(DefaultPlugins)
+        .add_plugin(InstancedSpriteRenderPlugin)
+        .add_plugin(InstancedSpriteMaterialPlugin::<PulseMaterial>::default())
+        .add_startup_system(setup)
+        .run();
+}
@@ -2,10 +2,10 @@ use bevy::{
     diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin},
     math::Vec3Swizzles,
     prelude::*,
-    utils::HashSet,
 };
 use bevy_sprite_instancing::{
-    InstancedSprite, InstancedSpriteRenderPlugin, InstancedSpritesheet, SpriteInstancingGroup,
+    AnimationMode, InstancedSprite, InstancedSpriteAnimation, InstancedSpriteRenderPlugin,
+    InstancedSpritesheet, SpriteInstancingGroup,
 };
 
 pub const ENTITY_COUNT: usize = 100000;
@@ -33,31 +33,31 @@ fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
         image,
         width_tiles: 32,
         height_tiles: 32,
+        ..default()
     };
 
-    let mut instancing_group0 = SpriteInstancingGroup {
-        entities: HashSet::new(),
-    };
-
-    let instancing_group0_id = commands.spawn_empty().id();
+    // Sprites are added to the group through their `group_id`
+    let instancing_group0_id = commands
+        .spawn((SpriteInstancingGroup::default(), spritesheet0))
+        .id();
 
     for _ in 0..ENTITY_COUNT {
-        let id = commands
-            .spawn((
-                random_transform(16.0, 10.0),
-                InstancedSprite {
-                    group_id: instancing_group0_id,
-                    texture_index: 0,
-                },
-            ))
-            .id();
-
-        instancing_group0.entities.insert(id);
+        commands.spawn((
+            TransformBundle::from_transform(random_transform(16.0, 10.0)),
+            InstancedSprite {
+                group_id: instancing_group0_id,
+                texture_index: 0,
+                ..default()
+            },
+            InstancedSpriteAnimation {
+                first_index: 0,
+                frame_count: 5,
+                fps: 10.0,
+                mode: AnimationMode::Loop,
+                start_time: 0.0,
+            },
+        ));
     }
-
-    commands
-        .entity(instancing_group0_id)
-        .insert((instancing_group0, spritesheet0));
 }
 
 fn move_entities(mut query: Query<&mut Transform, With<InstancedSprite>>) {
@@ -66,19 +66,9 @@ fn move_entities(mut query: Query<&mut Transform, With<InstancedSprite>>) {
     }
 }
 
-fn animate_entities(mut query: Query<&mut InstancedSprite>, time: Res<Time>) {
-    let t = time.elapsed_seconds();
-    let animation_step = (t * 10.0) as u32 % 5;
-
-    for mut instance in query.iter_mut() {
-        instance.texture_index = animation_step;
-    }
-}
-
 fn handle_clicks(
     mut commands: Commands,
-    entities: Query<(Entity, &Transform, &InstancedSprite)>,
-    mut instance_groups: Query<&mut SpriteInstancingGroup>,
+    entities: Query<(Entity, &Transform), With<InstancedSprite>>,
     window: Query<&Window>,
     mouse_button: Res<Input<MouseButton>>,
 ) {
@@ -87,16 +77,12 @@ fn handle_clicks(
         let position = window.cursor_position().unwrap()
             - Vec2::new(window.width() / 2.0, window.height() / 2.0);
 
-        for (entity, transform, instance) in &entities {
+        for (entity, transform) in &entities {
             let pos = transform.translation.xy();
 
+            // Despawned sprites are removed from their instancing group automatically
             if pos.distance(position) < 10.0 {
                 commands.entity(entity).despawn();
-                instance_groups
-                    .get_mut(instance.group_id)
-                    .unwrap()
-                    .entities
-                    .remove(&entity);
             }
         }
     }
@@ -107,10 +93,9 @@ pub fn main() {
         .add_plugins(DefaultPlugins)
         .add_plugin(InstancedSpriteRenderPlugin)
         .add_plugin(LogDiagnosticsPlugin::default())
-        .add_plugin(FrameTimeDiagnosticsPlugin::default())
+        .add_plugin(FrameTimeDiagnosticsPlugin)
         .add_startup_system(setup)
         .add_system(move_entities)
-        .add_system(animate_entities)
         .add_system(handle_clicks)
         .run();
 }
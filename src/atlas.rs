@@ -0,0 +1,281 @@
+use bevy::{
+    prelude::*,
+    render::{
+        color::SrgbColorSpace,
+        render_resource::{
+            Extent3d, TextureDimension, TextureFormat, TextureViewDescriptor, TextureViewDimension,
+        },
+        renderer::RenderDevice,
+    },
+    utils::HashSet,
+};
+
+use super::{InstancedSpritesheet, SpriteInstancingGroup};
+
+type AtlasGroupQuery = (
+    Entity,
+    Ref<'static, Handle<TextureAtlas>>,
+    Option<&'static InstancedSpritesheet>,
+);
+
+// Keeps the `InstancedSpritesheet` of groups with a `Handle<TextureAtlas>` in sync with the atlas
+// asset, so groups can use the same atlases as `SpriteSheetBundle`s. The `texture_array` setting
+// of an existing spritesheet is kept.
+pub(super) fn sync_texture_atlas_spritesheets(
+    mut commands: Commands,
+    mut atlas_events: EventReader<AssetEvent<TextureAtlas>>,
+    atlases: Res<Assets<TextureAtlas>>,
+    groups: Query<AtlasGroupQuery, With<SpriteInstancingGroup>>,
+) {
+    let mut changed_atlases = HashSet::new();
+    for event in atlas_events.iter() {
+        match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+                changed_atlases.insert(handle.id());
+            }
+            AssetEvent::Removed { .. } => (),
+        }
+    }
+
+    for (entity, atlas_handle, spritesheet) in &groups {
+        if !atlas_handle.is_changed() && !changed_atlases.contains(&atlas_handle.id()) {
+            continue;
+        }
+        // Not loaded yet, picked up by its `Created` event
+        let Some(atlas) = atlases.get(&atlas_handle) else {
+            continue;
+        };
+
+        commands.entity(entity).insert(InstancedSpritesheet {
+            texture_array: spritesheet.is_some_and(|spritesheet| spritesheet.texture_array),
+            ..InstancedSpritesheet::from_texture_atlas(atlas)
+        });
+    }
+}
+
+// Tiles of a spritesheet with `texture_array` set, stacked as layers of a texture array
+#[derive(Component)]
+pub(super) struct SpritesheetTextureArray {
+    pub(super) image: Handle<Image>,
+}
+
+// (Re)builds the texture arrays of spritesheets with `texture_array` set when the spritesheet or
+// its image changes
+pub(super) fn build_spritesheet_texture_arrays(
+    mut commands: Commands,
+    mut image_events: EventReader<AssetEvent<Image>>,
+    mut images: ResMut<Assets<Image>>,
+    device: Res<RenderDevice>,
+    groups: Query<(
+        Entity,
+        Ref<InstancedSpritesheet>,
+        Option<&SpritesheetTextureArray>,
+    )>,
+) {
+    let mut changed_images = HashSet::new();
+    for event in image_events.iter() {
+        match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+                changed_images.insert(handle.id());
+            }
+            AssetEvent::Removed { .. } => (),
+        }
+    }
+
+    for (entity, spritesheet, texture_array) in &groups {
+        if !spritesheet.texture_array {
+            if texture_array.is_some() {
+                commands.entity(entity).remove::<SpritesheetTextureArray>();
+            }
+            continue;
+        }
+        if !spritesheet.is_changed() && !changed_images.contains(&spritesheet.image.id()) {
+            continue;
+        }
+        // Not loaded yet, picked up by its `Created` event
+        let Some(image) = images.get(&spritesheet.image) else {
+            continue;
+        };
+        let max_layers = device.limits().max_texture_array_layers;
+        let Some(array_image) = stack_spritesheet_tiles(&spritesheet, image, max_layers) else {
+            continue;
+        };
+
+        match texture_array {
+            Some(texture_array) => images.set_untracked(texture_array.image.clone(), array_image),
+            None => {
+                let image = images.add(array_image);
+                commands
+                    .entity(entity)
+                    .insert(SpritesheetTextureArray { image });
+            }
+        }
+    }
+}
+
+// Copies each tile of the spritesheet into a layer of a new texture array image, along with a mip
+// chain for 8-bit RGBA images. All tiles need to have the same size, and there can't be more of
+// them than `max_layers`.
+fn stack_spritesheet_tiles(
+    spritesheet: &InstancedSpritesheet,
+    image: &Image,
+    max_layers: u32,
+) -> Option<Image> {
+    let format = image.texture_descriptor.format;
+    let format_info = format.describe();
+    if format_info.block_dimensions != (1, 1) {
+        warn!("Compressed spritesheet images can't be split into texture array layers");
+        return None;
+    }
+    let pixel_size = format_info.block_size as usize;
+    let image_size = UVec2::new(
+        image.texture_descriptor.size.width,
+        image.texture_descriptor.size.height,
+    );
+
+    let (tile_size, tile_origins) = if spritesheet.tile_rects.is_empty() {
+        if spritesheet.width_tiles == 0 || spritesheet.height_tiles == 0 {
+            return None;
+        }
+        let tile_size = image_size / UVec2::new(spritesheet.width_tiles, spritesheet.height_tiles);
+        let tile_origins = (0..spritesheet.height_tiles)
+            .flat_map(|y| (0..spritesheet.width_tiles).map(move |x| UVec2::new(x, y) * tile_size))
+            .collect::<Vec<_>>();
+        (tile_size, tile_origins)
+    } else {
+        let tile_size = spritesheet.tile_rects[0].size().as_uvec2();
+        if spritesheet
+            .tile_rects
+            .iter()
+            .any(|rect| rect.size().as_uvec2() != tile_size)
+        {
+            warn!("Texture array spritesheets need equally sized tiles");
+            return None;
+        }
+        let tile_origins = spritesheet
+            .tile_rects
+            .iter()
+            .map(|rect| rect.min.as_uvec2())
+            .collect::<Vec<_>>();
+        (tile_size, tile_origins)
+    };
+
+    if tile_origins.len() > max_layers as usize {
+        warn!(
+            "Spritesheet has {} tiles, but texture arrays can't have more than {} layers",
+            tile_origins.len(),
+            max_layers
+        );
+        return None;
+    }
+    if tile_size.min_element() == 0
+        || tile_origins
+            .iter()
+            .any(|origin| (*origin + tile_size).cmpgt(image_size).any())
+    {
+        warn!("Spritesheet tiles don't fit into the spritesheet image");
+        return None;
+    }
+
+    // Down to 1x1
+    let mip_level_count = if is_rgba8(format) {
+        u32::BITS - tile_size.max_element().leading_zeros()
+    } else {
+        1
+    };
+
+    // Each layer is followed by its mip levels
+    let row_size = tile_size.x as usize * pixel_size;
+    let mut data = vec![];
+    for origin in &tile_origins {
+        let mut layer = Vec::with_capacity(row_size * tile_size.y as usize);
+        for y in origin.y..origin.y + tile_size.y {
+            let start = (y as usize * image_size.x as usize + origin.x as usize) * pixel_size;
+            layer.extend_from_slice(&image.data[start..start + row_size]);
+        }
+        data.extend_from_slice(&layer);
+
+        let mut level_size = tile_size;
+        for _ in 1..mip_level_count {
+            (layer, level_size) = downsample_rgba8(&layer, level_size, format_info.srgb);
+            data.extend_from_slice(&layer);
+        }
+    }
+
+    let mut array_image = Image {
+        data,
+        sampler_descriptor: image.sampler_descriptor.clone(),
+        texture_view_descriptor: Some(TextureViewDescriptor {
+            dimension: Some(TextureViewDimension::D2Array),
+            ..default()
+        }),
+        ..default()
+    };
+    array_image.texture_descriptor.size = Extent3d {
+        width: tile_size.x,
+        height: tile_size.y,
+        depth_or_array_layers: tile_origins.len() as u32,
+    };
+    array_image.texture_descriptor.dimension = TextureDimension::D2;
+    array_image.texture_descriptor.format = format;
+    array_image.texture_descriptor.mip_level_count = mip_level_count;
+
+    Some(array_image)
+}
+
+fn is_rgba8(format: TextureFormat) -> bool {
+    matches!(
+        format,
+        TextureFormat::Rgba8Unorm
+            | TextureFormat::Rgba8UnormSrgb
+            | TextureFormat::Bgra8Unorm
+            | TextureFormat::Bgra8UnormSrgb
+    )
+}
+
+// Next mip level of an 8-bit RGBA image, each texel being the average of (up to) 2x2 texels. The
+// color of sRGB images is averaged in linear space.
+fn downsample_rgba8(data: &[u8], size: UVec2, srgb: bool) -> (Vec<u8>, UVec2) {
+    let to_linear = |value: u8| {
+        let value = value as f32 / 255.0;
+        if srgb {
+            value.nonlinear_to_linear_srgb()
+        } else {
+            value
+        }
+    };
+    let from_linear = |value: f32| {
+        let value = if srgb {
+            value.linear_to_nonlinear_srgb()
+        } else {
+            value
+        };
+        (value * 255.0).round() as u8
+    };
+
+    let new_size = (size / 2).max(UVec2::ONE);
+    let mut new_data = Vec::with_capacity((new_size.x * new_size.y * 4) as usize);
+
+    for y in 0..new_size.y {
+        let rows = [2 * y, (2 * y + 1).min(size.y - 1)];
+        for x in 0..new_size.x {
+            let columns = [2 * x, (2 * x + 1).min(size.x - 1)];
+            for channel in 0..4 {
+                let texels = rows
+                    .iter()
+                    .flat_map(|&row| columns.iter().map(move |&column| (row, column)))
+                    .map(|(row, column)| data[((row * size.x + column) * 4) as usize + channel]);
+                // Alpha is always linear
+                if channel == 3 {
+                    let sum = texels.map(u32::from).sum::<u32>();
+                    new_data.push(((sum + 2) / 4) as u8);
+                } else {
+                    let sum = texels.map(to_linear).sum::<f32>();
+                    new_data.push(from_linear(sum / 4.0));
+                }
+            }
+        }
+    }
+
+    (new_data, new_size)
+}
@@ -0,0 +1,330 @@
+use std::mem::size_of;
+
+use bevy::{
+    prelude::*,
+    render::{
+        mesh::GpuBufferInfo,
+        render_asset::RenderAssets,
+        render_graph::{Node, NodeRunError, RenderGraphContext},
+        render_resource::{
+            BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout,
+            BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingResource, BindingType, Buffer,
+            BufferBindingType, BufferDescriptor, BufferUsages, CachedComputePipelineId,
+            ComputePassDescriptor, ComputePipelineDescriptor, PipelineCache, ShaderStages,
+        },
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+    },
+    utils::HashMap,
+};
+use bytemuck::{Pod, Zeroable};
+
+use super::{
+    extract::{ExtractedSpriteInstancingBuffer, ExtractedSpriteInstancingGroup},
+    shader::INSTANCE_CULLING_SHADER_HANDLE,
+    InstancedSpriteMesh, SpriteInstanceData,
+};
+
+// Views beyond this count disable culling of the group
+const MAX_CULLING_VIEWS: usize = 8;
+const WORKGROUP_SIZE: u32 = 64;
+
+// Culling inputs of a group culled on the GPU
+#[derive(Clone)]
+pub(super) struct ExtractedGpuCulling {
+    // Bounds of the group's mesh in sprite space
+    pub(super) mesh_bounds: Rect,
+    pub(super) view_rects: Vec<Rect>,
+}
+
+// Mirrors `CullingParams` in the culling shader
+#[derive(Pod, Zeroable, Clone, Copy)]
+#[repr(C)]
+struct CullingParams {
+    mesh_center: [f32; 2],
+    mesh_half_size: [f32; 2],
+    instance_count: u32,
+    view_count: u32,
+    _padding: [u32; 2],
+    views: [[f32; 4]; MAX_CULLING_VIEWS],
+}
+
+// Instances of a group which survived culling, drawn indirectly instead of the group's whole
+// instance buffer
+#[derive(Component, Clone)]
+pub struct GpuCulledInstances {
+    pub(super) culled_buffer: Buffer,
+    pub(super) args_buffer: Buffer,
+}
+
+struct GpuCullingBuffers {
+    instances: GpuCulledInstances,
+    params_buffer: Buffer,
+    // Capacity of the instance buffer the culled buffer was sized for
+    capacity: usize,
+}
+
+#[derive(Resource, Default)]
+pub(super) struct GpuInstanceCulling {
+    buffers: HashMap<Entity, GpuCullingBuffers>,
+    // Bind group and instance count of each group to cull this frame
+    jobs: Vec<(BindGroup, u32)>,
+}
+
+#[derive(Resource)]
+pub(super) struct InstanceCullingPipeline {
+    layout: BindGroupLayout,
+    pipeline: CachedComputePipelineId,
+}
+
+pub(super) struct InstanceCullingNode;
+
+impl GpuCullingBuffers {
+    fn new(device: &RenderDevice, capacity: usize) -> Self {
+        let culled_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Culled sprite instance buffer"),
+            usage: BufferUsages::STORAGE,
+            size: (capacity * size_of::<SpriteInstanceData>()) as u64,
+            mapped_at_creation: false,
+        });
+        let args_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Culled sprite instance draw arguments"),
+            usage: BufferUsages::INDIRECT | BufferUsages::STORAGE | BufferUsages::COPY_DST,
+            size: size_of::<[u32; 5]>() as u64,
+            mapped_at_creation: false,
+        });
+        let params_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Sprite instance culling uniform"),
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+            size: size_of::<CullingParams>() as u64,
+            mapped_at_creation: false,
+        });
+
+        Self {
+            instances: GpuCulledInstances {
+                culled_buffer,
+                args_buffer,
+            },
+            params_buffer,
+            capacity,
+        }
+    }
+}
+
+impl GpuInstanceCulling {
+    pub(super) fn culled_instances(&self, entity: Entity) -> Option<&GpuCulledInstances> {
+        self.buffers.get(&entity).map(|buffers| &buffers.instances)
+    }
+}
+
+impl FromWorld for InstanceCullingPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let device = world.resource::<RenderDevice>();
+        let storage_entry = |binding, read_only| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+
+        let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("Sprite instance culling bind group layout"),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                // Instances
+                storage_entry(1, true),
+                // Culled instances
+                storage_entry(2, false),
+                // Draw arguments
+                storage_entry(3, false),
+            ],
+        });
+
+        let pipeline =
+            world
+                .resource::<PipelineCache>()
+                .queue_compute_pipeline(ComputePipelineDescriptor {
+                    label: Some("Sprite instance culling pipeline".into()),
+                    layout: vec![layout.clone()],
+                    push_constant_ranges: vec![],
+                    shader: INSTANCE_CULLING_SHADER_HANDLE.typed(),
+                    shader_defs: vec![],
+                    entry_point: "cull".into(),
+                });
+
+        Self { layout, pipeline }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(super) fn prepare_gpu_culling(
+    mut commands: Commands,
+    device: Res<RenderDevice>,
+    queue: Res<RenderQueue>,
+    pipeline: Res<InstanceCullingPipeline>,
+    instancing_mesh: Res<InstancedSpriteMesh>,
+    meshes: Res<RenderAssets<Mesh>>,
+    mut culling: ResMut<GpuInstanceCulling>,
+    groups: Query<(
+        Entity,
+        &ExtractedSpriteInstancingGroup,
+        &ExtractedSpriteInstancingBuffer,
+    )>,
+) {
+    let mut buffers = HashMap::new();
+    let mut jobs = vec![];
+
+    for (id, group, instancing_buffer) in &groups {
+        let Some(gpu_culling) = &group.gpu_culling else {
+            continue;
+        };
+        let Some(mesh) = instancing_mesh.get(group.mesh.as_ref(), &meshes) else {
+            continue;
+        };
+        let vertex_count = match &mesh.buffer_info {
+            GpuBufferInfo::Indexed { count, .. } => *count,
+            GpuBufferInfo::NonIndexed { vertex_count } => *vertex_count,
+        };
+
+        let group_buffers = match culling.buffers.remove(&id) {
+            Some(group_buffers) if group_buffers.capacity == instancing_buffer.capacity => {
+                group_buffers
+            }
+            _ => GpuCullingBuffers::new(&device, instancing_buffer.capacity),
+        };
+
+        // The instance count is reset here and then counted up by the culling pass
+        queue.write_buffer(
+            &group_buffers.instances.args_buffer,
+            0,
+            bytemuck::cast_slice(&[vertex_count, 0, 0, 0, 0]),
+        );
+
+        let mesh_bounds = gpu_culling.mesh_bounds;
+        let mut params = CullingParams {
+            mesh_center: mesh_bounds.center().into(),
+            mesh_half_size: mesh_bounds.half_size().into(),
+            instance_count: instancing_buffer.length as u32,
+            view_count: 0,
+            _padding: [0; 2],
+            views: [[0.0; 4]; MAX_CULLING_VIEWS],
+        };
+        if gpu_culling.view_rects.len() <= MAX_CULLING_VIEWS {
+            for (view, rect) in params.views.iter_mut().zip(&gpu_culling.view_rects) {
+                *view = [rect.min.x, rect.min.y, rect.max.x, rect.max.y];
+            }
+            params.view_count = gpu_culling.view_rects.len() as u32;
+        }
+        queue.write_buffer(&group_buffers.params_buffer, 0, bytemuck::bytes_of(&params));
+
+        if instancing_buffer.length > 0 {
+            let bind_group = device.create_bind_group(&BindGroupDescriptor {
+                label: Some("Sprite instance culling bind group"),
+                layout: &pipeline.layout,
+                entries: &[
+                    BindGroupEntry {
+                        binding: 0,
+                        resource: BindingResource::Buffer(
+                            group_buffers.params_buffer.as_entire_buffer_binding(),
+                        ),
+                    },
+                    BindGroupEntry {
+                        binding: 1,
+                        resource: BindingResource::Buffer(
+                            instancing_buffer.device_buffer.as_entire_buffer_binding(),
+                        ),
+                    },
+                    BindGroupEntry {
+                        binding: 2,
+                        resource: BindingResource::Buffer(
+                            group_buffers
+                                .instances
+                                .culled_buffer
+                                .as_entire_buffer_binding(),
+                        ),
+                    },
+                    BindGroupEntry {
+                        binding: 3,
+                        resource: BindingResource::Buffer(
+                            group_buffers
+                                .instances
+                                .args_buffer
+                                .as_entire_buffer_binding(),
+                        ),
+                    },
+                ],
+            });
+            jobs.push((bind_group, instancing_buffer.length as u32));
+        }
+
+        commands
+            .get_or_spawn(id)
+            .insert(group_buffers.instances.clone());
+        buffers.insert(id, group_buffers);
+    }
+
+    // Buffers of groups which are no longer culled on the GPU are dropped
+    culling.buffers = buffers;
+    culling.jobs = jobs;
+}
+
+impl InstanceCullingNode {
+    pub(super) const NAME: &'static str = "instanced_sprite_culling";
+}
+
+impl Node for InstanceCullingNode {
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let culling = world.resource::<GpuInstanceCulling>();
+        if culling.jobs.is_empty() {
+            return Ok(());
+        }
+        // Until the pipeline is compiled, culled groups draw no instances
+        let pipeline_id = world.resource::<InstanceCullingPipeline>().pipeline;
+        let Some(pipeline) = world
+            .resource::<PipelineCache>()
+            .get_compute_pipeline(pipeline_id)
+        else {
+            return Ok(());
+        };
+
+        let mut pass =
+            render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("Sprite instance culling pass"),
+                });
+        pass.set_pipeline(pipeline);
+
+        // Large groups need more workgroups than fit into a single dimension, they're laid out in
+        // rows instead
+        let max_workgroups = world
+            .resource::<RenderDevice>()
+            .limits()
+            .max_compute_workgroups_per_dimension;
+        for (bind_group, instance_count) in &culling.jobs {
+            let workgroups = instance_count.div_ceil(WORKGROUP_SIZE);
+            let workgroups_x = workgroups.min(max_workgroups);
+            pass.set_bind_group(0, bind_group, &[]);
+            pass.dispatch_workgroups(workgroups_x, workgroups.div_ceil(workgroups_x), 1);
+        }
+
+        Ok(())
+    }
+}
@@ -1,10 +1,12 @@
 use bevy::{
     ecs::system::{
         lifetimeless::{Read, SRes},
-        Res,
+        SystemParamItem,
     },
+    prelude::Mesh,
     render::{
         mesh::GpuBufferInfo,
+        render_asset::RenderAssets,
         render_phase::{
             PhaseItem, RenderCommand, RenderCommandResult, SetItemPipeline, TrackedRenderPass,
         },
@@ -13,7 +15,9 @@ use bevy::{
 };
 
 use super::{
-    extract::ExtractedSpriteInstancingBuffer, InstancedSpriteMesh, InstancedSpritesheetBindGroup,
+    culling::GpuCulledInstances,
+    extract::{ExtractedSpriteInstancingBuffer, ExtractedSpriteInstancingGroup},
+    InstancedSpriteMesh, InstancedSpritesheetBindGroup,
 };
 
 pub struct DrawSpritesInstanced;
@@ -26,34 +30,64 @@ pub(super) type DrawSpritesInstancedCommands = (
 );
 
 impl<P: PhaseItem> RenderCommand<P> for DrawSpritesInstanced {
-    type Param = SRes<InstancedSpriteMesh>;
+    type Param = (SRes<InstancedSpriteMesh>, SRes<RenderAssets<Mesh>>);
     type ViewWorldQuery = ();
     type ItemWorldQuery = (
+        Read<ExtractedSpriteInstancingGroup>,
         Read<ExtractedSpriteInstancingBuffer>,
         Read<InstancedSpritesheetBindGroup>,
+        Option<Read<GpuCulledInstances>>,
     );
 
     fn render<'w>(
         _item: &P,
         _view: (),
-        (instancing_buffer, instancing_spritesheet): (
+        (group, instancing_buffer, instancing_spritesheet, culled_instances): (
+            &'w ExtractedSpriteInstancingGroup,
             &'w ExtractedSpriteInstancingBuffer,
             &'w InstancedSpritesheetBindGroup,
+            Option<&'w GpuCulledInstances>,
         ),
-        instancing_mesh: Res<'w, InstancedSpriteMesh>,
+        (instancing_mesh, meshes): SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
-        let instancing_mesh = instancing_mesh.into_inner();
+        let Some(mesh) = instancing_mesh
+            .into_inner()
+            .get(group.mesh.as_ref(), meshes.into_inner())
+        else {
+            return RenderCommandResult::Failure;
+        };
+        let instances = 0..instancing_buffer.length as u32;
 
-        pass.set_vertex_buffer(0, instancing_mesh.quad.vertex_buffer.slice(..));
-        pass.set_vertex_buffer(1, instancing_buffer.device_buffer.slice(..));
+        pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+        // Otherwise bound as a storage buffer in the spritesheet bind group
+        if !instancing_buffer.storage {
+            pass.set_vertex_buffer(1, instancing_buffer.device_buffer.slice(..));
+        }
         pass.set_bind_group(1, &instancing_spritesheet.bind_group, &[]);
 
-        match &instancing_mesh.quad.buffer_info {
-            GpuBufferInfo::NonIndexed { vertex_count } => {
-                pass.draw(0..*vertex_count, 0..instancing_buffer.length as u32);
+        // The instance count of culled instances is only known on the GPU
+        match (&mesh.buffer_info, culled_instances) {
+            (
+                GpuBufferInfo::Indexed {
+                    buffer,
+                    count,
+                    index_format,
+                },
+                culled_instances,
+            ) => {
+                pass.set_index_buffer(buffer.slice(..), 0, *index_format);
+                match culled_instances {
+                    Some(culled) => pass.draw_indexed_indirect(&culled.args_buffer, 0),
+                    None => pass.draw_indexed(0..*count, 0, instances),
+                }
+            }
+            (GpuBufferInfo::NonIndexed { .. }, Some(culled)) => {
+                pass.draw_indirect(&culled.args_buffer, 0);
+            }
+            (GpuBufferInfo::NonIndexed { vertex_count }, None) => {
+                pass.draw(0..*vertex_count, instances);
             }
-            _ => todo!(),
         }
 
         RenderCommandResult::Success
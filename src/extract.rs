@@ -1,22 +1,44 @@
-use std::mem::size_of;
+use std::{mem::size_of, ops::Range, sync::Once};
 
 use bevy::{
-    math::Vec3Swizzles,
+    math::{Affine3A, Vec3Swizzles},
     prelude::*,
     render::{
         render_resource::{Buffer, BufferDescriptor, BufferInitDescriptor, BufferUsages},
         renderer::{RenderDevice, RenderQueue},
+        view::RenderLayers,
         Extract,
     },
-    utils::HashMap,
+    utils::{Entry, HashMap, HashSet},
 };
 
-use super::{InstancedSprite, InstancedSpritesheet, SpriteInstanceData, SpriteInstancingGroup};
+use super::{
+    atlas::SpritesheetTextureArray,
+    culling::ExtractedGpuCulling,
+    pipeline::{InstancingCapabilities, MAX_UNIFORM_TILE_RECTS},
+    InstanceSortMode, InstancedSprite, InstancedSpriteAnimation, InstancedSpritesheet,
+    SpriteBlendMode, SpriteInstanceData, SpriteInstancingGroup, SpriteSizeMode,
+};
 
 #[derive(Resource, Default)]
 pub struct ExtractedComponentCache {
-    instancing_buffers: HashMap<Entity, ExtractedSpriteInstancingBuffer>,
+    instancing_buffers: HashMap<Entity, (ExtractedSpriteInstancingBuffer, InstanceSlots)>,
     spritesheets: HashMap<Entity, ExtractedInstancedSpritesheet>,
+    group_uniforms: HashMap<Entity, (Buffer, [f32; 4])>,
+    // Inputs of each group's last upload which aren't covered by change detection
+    upload_states: HashMap<Entity, GroupUploadState>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct GroupUploadState {
+    default_size: Vec2,
+    mesh_bounds: Rect,
+}
+
+#[derive(Clone, Copy)]
+struct CameraView {
+    rect: Option<Rect>,
+    layers: RenderLayers,
 }
 
 #[derive(Component, Clone)]
@@ -24,24 +46,49 @@ pub struct ExtractedSpriteInstancingBuffer {
     pub(super) device_buffer: Buffer,
     pub(super) length: usize,
     pub(super) capacity: usize,
+    // Read by the vertex shader as a storage buffer instead of instance vertex attributes
+    pub(super) storage: bool,
+}
+
+// CPU-side mirror of an instancing buffer, keeping a stable slot for each entity so only the
+// changed parts of the buffer have to be written
+#[derive(Default)]
+pub struct InstanceSlots {
+    slots: HashMap<Entity, usize>,
+    entities: Vec<Entity>,
+    data: Vec<SpriteInstanceData>,
+}
+
+#[derive(Component, Clone)]
+pub struct ExtractedSpriteInstancingGroup {
+    pub(super) mesh: Option<Handle<Mesh>>,
+    pub(super) gpu_culling: Option<ExtractedGpuCulling>,
+    pub(super) visible: bool,
+    pub(super) render_layers: RenderLayers,
+    pub(super) z_layer: f32,
+    pub(super) blend_mode: SpriteBlendMode,
+    pub(super) uniform_buffer: Buffer,
 }
 
 #[derive(Component, Clone)]
 pub struct ExtractedInstancedSpritesheet {
     pub(super) size_buffer: Buffer,
+    pub(super) rects_buffer: Buffer,
     pub(super) image: Handle<Image>,
+    pub(super) texture_array: bool,
 }
 
 impl ExtractedSpriteInstancingBuffer {
     const INITIAL_CAPACITY: usize = 1024;
     const CAPACITY_INCREMENT: usize = 512;
 
-    pub fn new(device: &RenderDevice) -> Self {
-        let device_buffer = Self::create_buffer(device, Self::INITIAL_CAPACITY);
+    pub fn new(device: &RenderDevice, storage: bool) -> Self {
+        let device_buffer = Self::create_buffer(device, Self::INITIAL_CAPACITY, storage);
         Self {
             device_buffer,
             length: 0,
             capacity: Self::INITIAL_CAPACITY,
+            storage,
         }
     }
 
@@ -58,10 +105,38 @@ impl ExtractedSpriteInstancingBuffer {
         self.length = data.len();
     }
 
-    fn create_buffer(device: &RenderDevice, capacity: usize) -> Buffer {
+    pub fn upload_ranges(
+        &mut self,
+        device: &RenderDevice,
+        queue: &RenderQueue,
+        data: &[SpriteInstanceData],
+        ranges: &[Range<usize>],
+    ) {
+        if data.len() > self.capacity {
+            // A new buffer has to be filled completely
+            self.upload(device, queue, data);
+            return;
+        }
+
+        for range in ranges {
+            queue.write_buffer(
+                &self.device_buffer,
+                Self::buffer_size(range.start),
+                bytemuck::cast_slice(&data[range.clone()]),
+            );
+        }
+        self.length = data.len();
+    }
+
+    fn create_buffer(device: &RenderDevice, capacity: usize, storage: bool) -> Buffer {
+        let mut usage = BufferUsages::COPY_DST | BufferUsages::VERTEX;
+        if storage {
+            usage |= BufferUsages::STORAGE;
+        }
+
         device.create_buffer(&BufferDescriptor {
             label: Some("Entity instancing device buffer"),
-            usage: BufferUsages::COPY_DST | BufferUsages::VERTEX,
+            usage,
             size: Self::buffer_size(capacity),
             mapped_at_creation: false,
         })
@@ -77,7 +152,7 @@ impl ExtractedSpriteInstancingBuffer {
         }
         info!("Result capacity: {}", self.capacity);
 
-        self.device_buffer = Self::create_buffer(device, self.capacity);
+        self.device_buffer = Self::create_buffer(device, self.capacity, self.storage);
     }
 
     const fn buffer_size(n_elements: usize) -> u64 {
@@ -85,18 +160,164 @@ impl ExtractedSpriteInstancingBuffer {
     }
 }
 
+impl InstanceSlots {
+    // Replaces the contents with `instances`, returning the (coalesced) slot ranges which differ
+    // from the previous contents. Entities no longer present are swap-removed.
+    pub fn update(&mut self, instances: &[(Entity, SpriteInstanceData)]) -> Vec<Range<usize>> {
+        let mut present = vec![false; self.entities.len()];
+        let mut dirty = vec![];
+
+        for (entity, instance) in instances {
+            if let Some(&slot) = self.slots.get(entity) {
+                present[slot] = true;
+                if bytemuck::bytes_of(&self.data[slot]) != bytemuck::bytes_of(instance) {
+                    self.data[slot] = *instance;
+                    dirty.push(slot);
+                }
+            } else {
+                let slot = self.entities.len();
+                self.slots.insert(*entity, slot);
+                self.entities.push(*entity);
+                self.data.push(*instance);
+                present.push(true);
+                dirty.push(slot);
+            }
+        }
+
+        let mut slot = 0;
+        while slot < self.entities.len() {
+            if present[slot] {
+                slot += 1;
+                continue;
+            }
+
+            self.slots.remove(&self.entities[slot]);
+            self.entities.swap_remove(slot);
+            self.data.swap_remove(slot);
+            present.swap_remove(slot);
+
+            if slot < self.entities.len() {
+                self.slots.insert(self.entities[slot], slot);
+                dirty.push(slot);
+            }
+        }
+
+        dirty.retain(|&slot| slot < self.entities.len());
+        dirty.sort_unstable();
+        dirty.dedup();
+
+        Self::coalesce(dirty)
+    }
+
+    // Same as `update`, but the slots follow the order of `instances`
+    pub fn update_ordered(
+        &mut self,
+        instances: &[(Entity, SpriteInstanceData)],
+    ) -> Vec<Range<usize>> {
+        let mut dirty = vec![];
+        let mut displaced = vec![];
+
+        for (slot, (entity, instance)) in instances.iter().enumerate() {
+            if slot == self.entities.len() {
+                self.entities.push(*entity);
+                self.data.push(*instance);
+            } else if self.entities[slot] != *entity {
+                displaced.push((self.entities[slot], slot));
+                self.entities[slot] = *entity;
+                self.data[slot] = *instance;
+            } else if bytemuck::bytes_of(&self.data[slot]) != bytemuck::bytes_of(instance) {
+                self.data[slot] = *instance;
+            } else {
+                continue;
+            }
+
+            self.slots.insert(*entity, slot);
+            dirty.push(slot);
+        }
+
+        displaced.extend(
+            self.entities
+                .drain(instances.len()..)
+                .enumerate()
+                .map(|(i, entity)| (entity, instances.len() + i)),
+        );
+        self.data.truncate(instances.len());
+
+        // Forget entities which didn't get a new slot
+        for (entity, slot) in displaced {
+            if self.slots.get(&entity) == Some(&slot) {
+                self.slots.remove(&entity);
+            }
+        }
+
+        Self::coalesce(dirty)
+    }
+
+    // Merges sorted slot indices into contiguous ranges
+    fn coalesce(dirty: Vec<usize>) -> Vec<Range<usize>> {
+        let mut ranges: Vec<Range<usize>> = vec![];
+        for slot in dirty {
+            match ranges.last_mut() {
+                Some(range) if range.end == slot => range.end += 1,
+                _ => ranges.push(slot..slot + 1),
+            }
+        }
+        ranges
+    }
+}
+
 impl ExtractedInstancedSpritesheet {
-    pub fn new(device: &RenderDevice, spritesheet: &InstancedSpritesheet) -> Self {
-        let size = [spritesheet.width_tiles, spritesheet.height_tiles];
+    pub fn new(
+        device: &RenderDevice,
+        spritesheet: &InstancedSpritesheet,
+        image: Handle<Image>,
+        storage_rects: bool,
+    ) -> Self {
+        let mut rects = spritesheet
+            .tile_rects
+            .iter()
+            .map(|rect| [rect.min.x, rect.min.y, rect.max.x, rect.max.y])
+            .collect::<Vec<_>>();
+        if !storage_rects && rects.len() > MAX_UNIFORM_TILE_RECTS {
+            warn!(
+                "Spritesheets can't have more than {} tiles without storage buffer support",
+                MAX_UNIFORM_TILE_RECTS
+            );
+            rects.truncate(MAX_UNIFORM_TILE_RECTS);
+        }
+
+        // Padded to 16 bytes
+        let size = [
+            spritesheet.width_tiles,
+            spritesheet.height_tiles,
+            rects.len() as u32,
+            0,
+        ];
         let size_buffer = device.create_buffer_with_data(&BufferInitDescriptor {
             label: Some("Instanced spritesheet size uniform"),
             contents: bytemuck::cast_slice(&size),
             usage: BufferUsages::UNIFORM,
         });
 
+        // Storage buffers can't be empty, grid spritesheets get a single unused rectangle. Uniform
+        // arrays have a fixed length.
+        let (rects_len, usage) = if storage_rects {
+            (rects.len().max(1), BufferUsages::STORAGE)
+        } else {
+            (MAX_UNIFORM_TILE_RECTS, BufferUsages::UNIFORM)
+        };
+        rects.resize(rects_len, [0.0; 4]);
+        let rects_buffer = device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("Instanced spritesheet tile rectangles"),
+            contents: bytemuck::cast_slice(&rects),
+            usage,
+        });
+
         Self {
             size_buffer,
-            image: spritesheet.image.clone(),
+            rects_buffer,
+            image,
+            texture_array: spritesheet.texture_array,
         }
     }
 }
@@ -107,14 +328,24 @@ impl ExtractedComponentCache {
         entity: Entity,
         device: &RenderDevice,
         queue: &RenderQueue,
-        data: &[SpriteInstanceData],
+        capabilities: &InstancingCapabilities,
+        instances: &[(Entity, SpriteInstanceData)],
+        ordered: bool,
     ) -> ExtractedSpriteInstancingBuffer {
-        let buffer = self.instancing_buffers.entry(entity).or_insert_with(|| {
+        let (buffer, slots) = self.instancing_buffers.entry(entity).or_insert_with(|| {
             info!("Creating a new instance buffer {:?}", entity);
-            ExtractedSpriteInstancingBuffer::new(device)
+            (
+                ExtractedSpriteInstancingBuffer::new(device, capabilities.storage_instances),
+                InstanceSlots::default(),
+            )
         });
 
-        buffer.upload(device, queue, data);
+        let dirty_ranges = if ordered {
+            slots.update_ordered(instances)
+        } else {
+            slots.update(instances)
+        };
+        buffer.upload_ranges(device, queue, &slots.data, &dirty_ranges);
         buffer.clone()
     }
 
@@ -122,56 +353,607 @@ impl ExtractedComponentCache {
         &mut self,
         entity: Entity,
         device: &RenderDevice,
+        capabilities: &InstancingCapabilities,
         spritesheet: &InstancedSpritesheet,
+        image: &Handle<Image>,
+        changed: bool,
     ) -> ExtractedInstancedSpritesheet {
-        let spritesheet = self.spritesheets.entry(entity).or_insert_with(|| {
-            info!("Creating a new spritesheet buffer {:?}", entity);
-            ExtractedInstancedSpritesheet::new(device, spritesheet)
+        match self.spritesheets.entry(entity) {
+            Entry::Occupied(mut entry) => {
+                if changed {
+                    entry.insert(ExtractedInstancedSpritesheet::new(
+                        device,
+                        spritesheet,
+                        image.clone(),
+                        capabilities.storage_rects,
+                    ));
+                }
+                entry.get().clone()
+            }
+            Entry::Vacant(entry) => {
+                info!("Creating a new spritesheet buffer {:?}", entity);
+                entry
+                    .insert(ExtractedInstancedSpritesheet::new(
+                        device,
+                        spritesheet,
+                        image.clone(),
+                        capabilities.storage_rects,
+                    ))
+                    .clone()
+            }
+        }
+    }
+
+    fn update_group_uniform(
+        &mut self,
+        entity: Entity,
+        device: &RenderDevice,
+        queue: &RenderQueue,
+        group: &SpriteInstancingGroup,
+        time_wrap_period: f32,
+    ) -> Buffer {
+        let alpha_cutoff = match group.blend_mode {
+            SpriteBlendMode::AlphaMask(cutoff) => cutoff,
+            _ => 0.5,
+        };
+        // Padded to 16 bytes
+        let uniform = [alpha_cutoff, time_wrap_period, 0.0, 0.0];
+
+        match self.group_uniforms.entry(entity) {
+            Entry::Occupied(mut entry) => {
+                let (buffer, contents) = entry.get_mut();
+                if *contents != uniform {
+                    queue.write_buffer(buffer, 0, bytemuck::cast_slice(&uniform));
+                    *contents = uniform;
+                }
+                buffer.clone()
+            }
+            Entry::Vacant(entry) => {
+                let buffer = device.create_buffer_with_data(&BufferInitDescriptor {
+                    label: Some("Sprite instancing group uniform"),
+                    contents: bytemuck::cast_slice(&uniform),
+                    usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+                });
+                entry.insert((buffer, uniform)).0.clone()
+            }
+        }
+    }
+
+    fn remove_despawned_groups(&mut self, is_alive: impl Fn(Entity) -> bool) {
+        self.instancing_buffers.retain(|id, _| is_alive(*id));
+        self.spritesheets.retain(|id, _| is_alive(*id));
+        self.group_uniforms.retain(|id, _| is_alive(*id));
+        self.upload_states.retain(|id, _| is_alive(*id));
+    }
+}
+
+type SpriteTransforms = (
+    AnyOf<(&'static GlobalTransform, &'static Transform)>,
+    Option<&'static Parent>,
+);
+type EntityVisibility = (
+    Option<&'static ComputedVisibility>,
+    Option<&'static Visibility>,
+);
+type SpriteQuery = (
+    SpriteTransforms,
+    &'static InstancedSprite,
+    Option<&'static InstancedSpriteAnimation>,
+    EntityVisibility,
+);
+
+static MISSING_GLOBAL_TRANSFORM_WARNING: Once = Once::new();
+static PARENTED_LOCAL_TRANSFORM_WARNING: Once = Once::new();
+
+// Sprites without a GlobalTransform (e.g. when transform propagation is not used) are only drawn
+// at their local Transform when the group opts into it
+fn sprite_affine(
+    ((global, local), parent): (
+        (Option<&GlobalTransform>, Option<&Transform>),
+        Option<&Parent>,
+    ),
+    local_transforms: bool,
+) -> Option<Affine3A> {
+    match (global, local) {
+        (Some(global), _) => Some(global.affine()),
+        (None, Some(local)) if local_transforms => {
+            if parent.is_some() {
+                PARENTED_LOCAL_TRANSFORM_WARNING.call_once(|| {
+                    warn!(
+                        "Instanced sprites with a parent but without a GlobalTransform are drawn \
+                        at their local Transform"
+                    );
+                });
+            }
+            Some(local.compute_affine())
+        }
+        _ => {
+            MISSING_GLOBAL_TRANSFORM_WARNING.call_once(|| {
+                warn!(
+                    "Instanced sprites without a GlobalTransform aren't drawn, set \
+                    `SpriteInstancingGroup::local_transforms` to use their Transform instead"
+                );
+            });
+            None
+        }
+    }
+}
+
+// Entities without visibility components are always visible. `ComputedVisibility` also accounts
+// for hidden ancestors, but is only updated for entities which have a `Visibility` as well.
+fn is_visible((computed, visibility): (Option<&ComputedVisibility>, Option<&Visibility>)) -> bool {
+    match (computed, visibility) {
+        (Some(computed), Some(_)) => computed.is_visible_in_hierarchy(),
+        (_, visibility) => visibility != Some(&Visibility::Hidden),
+    }
+}
+
+// World-space rectangle seen by a 2D camera
+fn camera_view_rect(camera: &Camera, transform: &GlobalTransform) -> Option<Rect> {
+    let corners = [
+        Vec3::new(-1.0, -1.0, 0.0),
+        Vec3::new(1.0, -1.0, 0.0),
+        Vec3::new(1.0, 1.0, 0.0),
+        Vec3::new(-1.0, 1.0, 0.0),
+    ];
+    let mut rect: Option<Rect> = None;
+
+    for corner in corners {
+        let point = camera.ndc_to_world(transform, corner)?.xy();
+        rect = Some(match rect {
+            Some(rect) => rect.union_point(point),
+            None => Rect::from_corners(point, point),
         });
+    }
+
+    rect
+}
 
-        spritesheet.clone()
-        // TODO handle spritesheet changes?
+// Size of the group's grid tiles without a `custom_size`. Tile sizes are unknown until the
+// spritesheet image is loaded, unit size is used until then.
+fn default_sprite_size(
+    group: &SpriteInstancingGroup,
+    spritesheet: &InstancedSpritesheet,
+    images: &Assets<Image>,
+) -> Vec2 {
+    let size_tiles = Vec2::new(
+        spritesheet.width_tiles as f32,
+        spritesheet.height_tiles as f32,
+    );
+    if group.size_mode == SpriteSizeMode::Unit || size_tiles.min_element() == 0.0 {
+        return Vec2::ONE;
     }
+
+    images
+        .get(&spritesheet.image)
+        .map_or(Vec2::ONE, |image| image.size() / size_tiles)
 }
 
+// Bounds of the group's mesh in sprite space, the default quad spans -0.5..0.5. Meshes which
+// aren't loaded yet aren't drawn, so their bounds don't matter.
+fn mesh_bounds(group: &SpriteInstancingGroup, meshes: &Assets<Mesh>) -> Rect {
+    group
+        .mesh
+        .as_ref()
+        .and_then(|mesh| meshes.get(mesh))
+        .and_then(Mesh::compute_aabb)
+        .map_or(
+            Rect::from_center_half_size(Vec2::ZERO, Vec2::splat(0.5)),
+            |aabb| Rect::from_center_half_size(aabb.center.xy(), aabb.half_extents.xy()),
+        )
+}
+
+// World-space bounding rectangle of the instance's mesh
+fn instance_bounds(instance: &SpriteInstanceData, mesh_bounds: Rect) -> Rect {
+    let [xx, xy, yx, yy] = instance.i_rotation_scale;
+    let rotation_scale = Mat2::from_cols_array(&instance.i_rotation_scale);
+    let half = mesh_bounds.half_size();
+    let center =
+        instance.i_position.xy() + rotation_scale * (mesh_bounds.center() - instance.i_anchor);
+    let half_size = Vec2::new(
+        xx.abs() * half.x + yx.abs() * half.y,
+        xy.abs() * half.x + yy.abs() * half.y,
+    );
+
+    Rect::from_center_half_size(center, half_size)
+}
+
+// Builds the instance data of a group's members, skipping those outside of all `view_rects` when
+// they're given
+fn collect_instance_data(
+    group: &SpriteInstancingGroup,
+    spritesheet: &InstancedSpritesheet,
+    entity_query: &Query<SpriteQuery>,
+    upload_state: GroupUploadState,
+    view_rects: Option<&[Rect]>,
+) -> Vec<(Entity, SpriteInstanceData)> {
+    let mut instancing_data = vec![];
+
+    for entity in &group.entities {
+        // Despawned members are pruned from the group in the main world, skip them until then
+        let Ok((transforms, instance, animation, visibility)) = entity_query.get(*entity) else {
+            continue;
+        };
+        if !is_visible(visibility) {
+            continue;
+        }
+        let Some(affine) = sprite_affine(transforms, group.local_transforms) else {
+            continue;
+        };
+        let tex_index = animation.map_or(instance.texture_index, |animation| animation.first_index);
+        // Tiles of non-uniform spritesheets keep their own size
+        let tile_rect = match group.size_mode {
+            SpriteSizeMode::TileSize => spritesheet.tile_rects.get(tex_index as usize),
+            SpriteSizeMode::Unit => None,
+        };
+        let size = instance
+            .custom_size
+            .or_else(|| tile_rect.map(Rect::size))
+            .unwrap_or(upload_state.default_size);
+        let basis = affine.matrix3;
+
+        let mut instance_data = SpriteInstanceData {
+            i_position: affine.translation.into(),
+            i_rotation_scale: [
+                basis.x_axis.x * size.x,
+                basis.x_axis.y * size.x,
+                basis.y_axis.x * size.y,
+                basis.y_axis.y * size.y,
+            ],
+            i_tex_index: tex_index,
+            i_color: instance.color.as_linear_rgba_f32(),
+            i_animation: [1, 0],
+            i_animation_time: [0.0, 0.0],
+            i_anchor: instance.anchor.as_vec(),
+            i_flags: 0,
+        };
+        if instance.flip_x {
+            instance_data.i_flags |= SpriteInstanceData::FLAG_FLIP_X;
+        }
+        if instance.flip_y {
+            instance_data.i_flags |= SpriteInstanceData::FLAG_FLIP_Y;
+        }
+        if let Some(animation) = animation {
+            instance_data.i_animation = [animation.frame_count, animation.mode as u32];
+            instance_data.i_animation_time = [animation.fps, animation.start_time];
+        }
+
+        if let Some(view_rects) = view_rects {
+            let bounds = instance_bounds(&instance_data, upload_state.mesh_bounds);
+            if view_rects
+                .iter()
+                .all(|view_rect| view_rect.intersect(bounds).is_empty())
+            {
+                continue;
+            }
+        }
+
+        instancing_data.push((*entity, instance_data));
+    }
+
+    instancing_data
+}
+
+// Orders the instances back-to-front, ties are broken by entity so the order is deterministic
+fn sort_instance_data(instances: &mut [(Entity, SpriteInstanceData)], sort_mode: InstanceSortMode) {
+    match sort_mode {
+        InstanceSortMode::Unsorted => (),
+        InstanceSortMode::ByZ => instances.sort_unstable_by(|(a_id, a), (b_id, b)| {
+            a.i_position
+                .z
+                .total_cmp(&b.i_position.z)
+                .then(a_id.cmp(b_id))
+        }),
+        InstanceSortMode::ByY => instances.sort_unstable_by(|(a_id, a), (b_id, b)| {
+            b.i_position
+                .y
+                .total_cmp(&a.i_position.y)
+                .then(a_id.cmp(b_id))
+        }),
+    }
+}
+
+type ChangedSpriteFilter = Or<(
+    Changed<Transform>,
+    Changed<GlobalTransform>,
+    Changed<InstancedSprite>,
+    Changed<InstancedSpriteAnimation>,
+    Changed<Visibility>,
+    // Moving under a hidden parent
+    Changed<Parent>,
+)>;
+
+// Hiding a parent only changes the `ComputedVisibility` of its children, which is updated every
+// frame and can't be used for change detection
+type ChangedParentVisibilityFilter = (Changed<Visibility>, With<Children>);
+
+type GroupQuery = (
+    Entity,
+    Ref<'static, InstancedSpritesheet>,
+    Option<Ref<'static, SpritesheetTextureArray>>,
+    Ref<'static, SpriteInstancingGroup>,
+    EntityVisibility,
+    Option<Ref<'static, RenderLayers>>,
+);
+
+#[allow(clippy::too_many_arguments)]
 pub(super) fn extract_instancing_groups(
     mut commands: Commands,
-    entity_query: Extract<Query<(&Transform, &InstancedSprite)>>,
-    instancing_groups: Extract<Query<(Entity, &InstancedSpritesheet, &SpriteInstancingGroup)>>,
+    entity_query: Extract<Query<SpriteQuery>>,
+    changed_entity_query: Extract<Query<&InstancedSprite, ChangedSpriteFilter>>,
+    changed_parent_visibility: Extract<Query<(), ChangedParentVisibilityFilter>>,
+    instancing_groups: Extract<Query<GroupQuery>>,
+    cameras: Extract<Query<(&Camera, &GlobalTransform, Option<&RenderLayers>)>>,
+    images: Extract<Res<Assets<Image>>>,
+    meshes: Extract<Res<Assets<Mesh>>>,
+    time: Extract<Res<Time>>,
     queue: Res<RenderQueue>,
     device: Res<RenderDevice>,
+    capabilities: Res<InstancingCapabilities>,
     mut extracted_cache: ResMut<ExtractedComponentCache>,
 ) {
-    if entity_query.is_empty() {
-        return;
+    let views = cameras
+        .iter()
+        .filter(|(camera, ..)| camera.is_active)
+        .map(|(camera, transform, layers)| CameraView {
+            rect: camera_view_rect(camera, transform),
+            layers: layers.copied().unwrap_or_default(),
+        })
+        .collect::<Vec<_>>();
+
+    // Only static groups care about which members changed since the last extraction. Any parent's
+    // visibility change re-uploads all of them, as it's not known which sprites it affects.
+    let parent_visibility_changed = !changed_parent_visibility.is_empty();
+    let mut changed_groups = HashSet::new();
+    if instancing_groups
+        .iter()
+        .any(|(_, _, _, group, ..)| group.is_static)
+    {
+        changed_groups.extend(changed_entity_query.iter().map(|sprite| sprite.group_id));
     }
 
-    for (id, spritesheet, group) in &instancing_groups {
-        // TODO handle empty groups somehow
-        assert!(!group.entities.is_empty());
+    for (id, spritesheet, texture_array, group, visibility, layers) in &instancing_groups {
+        // Texture array spritesheets can't be drawn until their array is built
+        let (image, image_changed) = match (spritesheet.texture_array, &texture_array) {
+            (false, _) => (&spritesheet.image, false),
+            (true, Some(texture_array)) => (&texture_array.image, texture_array.is_changed()),
+            (true, None) => continue,
+        };
 
-        let mut instancing_data = vec![];
+        // Only cameras which see the group are used for culling
+        let render_layers = layers.as_deref().copied().unwrap_or_default();
+        let group_views = views
+            .iter()
+            .filter(|view| view.layers.intersects(&render_layers));
+        let view_rects = group_views.filter_map(|view| view.rect).collect::<Vec<_>>();
 
-        for entity in &group.entities {
-            // TODO handle entity removal without group update somehow
-            let (transform, instance) = entity_query.get(*entity).unwrap();
+        let gpu_culling = group.gpu_culling
+            && group.culling
+            && group.sort_mode == InstanceSortMode::Unsorted
+            && capabilities.gpu_culling;
+        // Culling while uploading would re-upload static groups whenever a camera moves
+        let culling = group.culling && !group.is_static && !gpu_culling && !view_rects.is_empty();
+        let upload_state = GroupUploadState {
+            default_size: default_sprite_size(&group, &spritesheet, &images),
+            mesh_bounds: mesh_bounds(&group, &meshes),
+        };
+        let upload_state_changed =
+            extracted_cache.upload_states.insert(id, upload_state) != Some(upload_state);
+        let needs_upload = !group.is_static
+            || group.is_changed()
+            || spritesheet.is_changed()
+            || changed_groups.contains(&id)
+            || parent_visibility_changed
+            || upload_state_changed;
 
-            instancing_data.push(SpriteInstanceData {
-                i_position: transform.translation,
-                i_scale: transform.scale.xy(),
-                i_tex_index: instance.texture_index,
-            });
-        }
+        let buffer = match extracted_cache.instancing_buffers.get(&id) {
+            Some((buffer, _)) if !needs_upload => buffer.clone(),
+            _ => {
+                let mut instancing_data = collect_instance_data(
+                    &group,
+                    &spritesheet,
+                    &entity_query,
+                    upload_state,
+                    culling.then_some(view_rects.as_slice()),
+                );
+                sort_instance_data(&mut instancing_data, group.sort_mode);
+
+                extracted_cache.update_instancing_buffer(
+                    id,
+                    device.as_ref(),
+                    queue.as_ref(),
+                    &capabilities,
+                    &instancing_data,
+                    group.sort_mode != InstanceSortMode::Unsorted,
+                )
+            }
+        };
+        let spritesheet = extracted_cache.update_instanced_spritesheet(
+            id,
+            device.as_ref(),
+            &capabilities,
+            &spritesheet,
+            image,
+            spritesheet.is_changed() || image_changed,
+        );
 
-        let buffer = extracted_cache.update_instancing_buffer(
+        let uniform_buffer = extracted_cache.update_group_uniform(
             id,
             device.as_ref(),
             queue.as_ref(),
-            &instancing_data,
+            &group,
+            time.wrap_period().as_secs_f32(),
+        );
+        let extracted_group = ExtractedSpriteInstancingGroup {
+            mesh: group.mesh.clone(),
+            gpu_culling: gpu_culling.then_some(ExtractedGpuCulling {
+                mesh_bounds: upload_state.mesh_bounds,
+                view_rects,
+            }),
+            visible: is_visible(visibility),
+            render_layers,
+            z_layer: group.z_layer,
+            blend_mode: group.blend_mode,
+            uniform_buffer,
+        };
+
+        commands
+            .get_or_spawn(id)
+            .insert((extracted_group, buffer, spritesheet));
+    }
+
+    extracted_cache.remove_despawned_groups(|id| instancing_groups.contains(id));
+}
+
+// Views don't carry their camera's render layers into the render world, so groups can't be
+// matched against them otherwise
+pub(super) fn extract_camera_render_layers(
+    mut commands: Commands,
+    cameras: Extract<Query<(Entity, &Camera, Option<&RenderLayers>)>>,
+) {
+    for (entity, camera, layers) in &cameras {
+        if camera.is_active {
+            commands
+                .get_or_spawn(entity)
+                .insert(layers.copied().unwrap_or_default());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::Entity;
+    use bytemuck::Zeroable;
+
+    use super::{InstanceSlots, SpriteInstanceData};
+
+    fn instance(tex_index: u32) -> SpriteInstanceData {
+        SpriteInstanceData {
+            i_tex_index: tex_index,
+            ..SpriteInstanceData::zeroed()
+        }
+    }
+
+    fn instances(entities: &[(u32, u32)]) -> Vec<(Entity, SpriteInstanceData)> {
+        entities
+            .iter()
+            .map(|&(entity, tex_index)| (Entity::from_raw(entity), instance(tex_index)))
+            .collect()
+    }
+
+    // Checks the slots against the expected (entity, texture index) of each slot
+    fn assert_slots(slots: &InstanceSlots, expected: &[(u32, u32)]) {
+        let entities: Vec<_> = expected
+            .iter()
+            .map(|&(entity, _)| Entity::from_raw(entity))
+            .collect();
+        assert_eq!(slots.entities, entities);
+        assert_eq!(slots.data.len(), expected.len());
+        assert_eq!(slots.slots.len(), expected.len());
+
+        for (slot, &(entity, tex_index)) in expected.iter().enumerate() {
+            assert_eq!(slots.slots[&Entity::from_raw(entity)], slot);
+            assert_eq!(slots.data[slot].i_tex_index, tex_index);
+        }
+    }
+
+    #[test]
+    fn update_removes_from_middle_and_tail() {
+        let mut slots = InstanceSlots::default();
+        let ranges = slots.update(&instances(&[(0, 0), (1, 1), (2, 2), (3, 3), (4, 4)]));
+        assert_eq!(ranges, vec![0..5]);
+        assert_slots(&slots, &[(0, 0), (1, 1), (2, 2), (3, 3), (4, 4)]);
+
+        // The last slot is moved into the hole
+        let ranges = slots.update(&instances(&[(0, 0), (1, 1), (3, 3), (4, 4)]));
+        assert_eq!(ranges, vec![2..3]);
+        assert_slots(&slots, &[(0, 0), (1, 1), (4, 4), (3, 3)]);
+
+        // Nothing is moved when removing the last slot
+        let ranges = slots.update(&instances(&[(0, 0), (1, 1), (4, 4)]));
+        assert!(ranges.is_empty());
+        assert_slots(&slots, &[(0, 0), (1, 1), (4, 4)]);
+
+        let ranges = slots.update(&[]);
+        assert!(ranges.is_empty());
+        assert_slots(&slots, &[]);
+    }
+
+    #[test]
+    fn update_moves_changed_entity() {
+        let mut slots = InstanceSlots::default();
+        slots.update(&instances(&[(0, 0), (1, 1), (2, 2), (3, 3)]));
+
+        // Entity 3 changes in its old slot, then replaces the removed entity 0
+        let ranges = slots.update(&instances(&[(1, 1), (2, 2), (3, 30)]));
+        assert_eq!(ranges, vec![0..1]);
+        assert_slots(&slots, &[(3, 30), (1, 1), (2, 2)]);
+
+        // A changed entity next to a moved one
+        let ranges = slots.update(&instances(&[(1, 10), (2, 2)]));
+        assert_eq!(ranges, vec![0..2]);
+        assert_slots(&slots, &[(2, 2), (1, 10)]);
+    }
+
+    #[test]
+    fn update_ordered_follows_order() {
+        let mut slots = InstanceSlots::default();
+        let ranges = slots.update_ordered(&instances(&[(0, 0), (1, 1), (2, 2)]));
+        assert_eq!(ranges, vec![0..3]);
+        assert_slots(&slots, &[(0, 0), (1, 1), (2, 2)]);
+
+        let ranges = slots.update_ordered(&instances(&[(2, 2), (0, 0), (1, 1)]));
+        assert_eq!(ranges, vec![0..3]);
+        assert_slots(&slots, &[(2, 2), (0, 0), (1, 1)]);
+
+        let ranges = slots.update_ordered(&instances(&[(2, 2), (0, 0), (1, 1)]));
+        assert!(ranges.is_empty());
+        assert_slots(&slots, &[(2, 2), (0, 0), (1, 1)]);
+
+        // Entity 0 is removed and entity 1 moves up
+        let ranges = slots.update_ordered(&instances(&[(2, 2), (1, 1)]));
+        assert_eq!(ranges, vec![1..2]);
+        assert_slots(&slots, &[(2, 2), (1, 1)]);
+
+        let ranges = slots.update_ordered(&instances(&[(2, 20), (1, 1), (3, 3)]));
+        assert_eq!(ranges, vec![0..1, 2..3]);
+        assert_slots(&slots, &[(2, 20), (1, 1), (3, 3)]);
+    }
+
+    #[test]
+    fn switching_between_ordered_and_unordered() {
+        let mut slots = InstanceSlots::default();
+        slots.update_ordered(&instances(&[(0, 0), (1, 1), (2, 2)]));
+
+        let ranges = slots.update(&instances(&[(2, 2), (0, 0)]));
+        assert_eq!(ranges, vec![1..2]);
+        assert_slots(&slots, &[(0, 0), (2, 2)]);
+
+        let ranges = slots.update_ordered(&instances(&[(2, 2), (0, 0)]));
+        assert_eq!(ranges, vec![0..2]);
+        assert_slots(&slots, &[(2, 2), (0, 0)]);
+
+        let ranges = slots.update(&instances(&[(0, 0), (3, 3), (2, 2)]));
+        assert_eq!(ranges, vec![2..3]);
+        assert_slots(&slots, &[(2, 2), (0, 0), (3, 3)]);
+
+        let ranges = slots.update_ordered(&instances(&[(2, 2), (0, 0)]));
+        assert!(ranges.is_empty());
+        assert_slots(&slots, &[(2, 2), (0, 0)]);
+    }
+
+    #[test]
+    fn coalesces_adjacent_slots() {
+        assert!(InstanceSlots::coalesce(vec![]).is_empty());
+        assert_eq!(
+            InstanceSlots::coalesce(vec![0, 1, 2, 5, 7, 8]),
+            vec![0..3, 5..6, 7..9]
         );
-        let spritesheet =
-            extracted_cache.update_instanced_spritesheet(id, device.as_ref(), spritesheet);
 
-        commands.get_or_spawn(id).insert((buffer, spritesheet));
+        let mut slots = InstanceSlots::default();
+        slots.update(&instances(&[(0, 0), (1, 1), (2, 2), (3, 3), (4, 4)]));
+        let ranges = slots.update(&instances(&[(0, 0), (1, 10), (2, 20), (3, 3), (4, 40)]));
+        assert_eq!(ranges, vec![1..3, 4..5]);
+        assert_slots(&slots, &[(0, 0), (1, 10), (2, 20), (3, 3), (4, 40)]);
     }
 }
@@ -0,0 +1,144 @@
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+
+use super::{InstancedSprite, SpriteInstancingGroup};
+
+// Removes despawned entities (or ones which no longer are sprites) from the instancing groups
+pub(super) fn prune_instancing_groups(
+    mut removed_sprites: RemovedComponents<InstancedSprite>,
+    mut groups: Query<&mut SpriteInstancingGroup>,
+    sprites: Query<(), With<InstancedSprite>>,
+) {
+    let removed = removed_sprites.iter().collect::<HashSet<_>>();
+
+    for mut group in &mut groups {
+        // Only touch the group if it actually has stale entries, so it isn't marked as changed
+        let has_stale_entries = if group.is_changed() {
+            group
+                .entities
+                .iter()
+                .any(|entity| !sprites.contains(*entity))
+        } else {
+            removed.iter().any(|entity| group.entities.contains(entity))
+        };
+
+        if has_stale_entries {
+            group.entities.retain(|entity| sprites.contains(*entity));
+        }
+    }
+}
+
+#[derive(Default)]
+pub(super) struct GroupMemberships {
+    // Group each sprite has been inserted into
+    groups: HashMap<Entity, Entity>,
+    // Sprites whose group entity has no SpriteInstancingGroup (yet)
+    pending: HashSet<Entity>,
+}
+
+// Keeps SpriteInstancingGroup::entities in sync with InstancedSprite::group_id
+pub(super) fn update_instancing_group_membership(
+    mut memberships: Local<GroupMemberships>,
+    mut removed_sprites: RemovedComponents<InstancedSprite>,
+    changed_sprites: Query<Entity, Changed<InstancedSprite>>,
+    sprites: Query<&InstancedSprite>,
+    mut groups: Query<&mut SpriteInstancingGroup>,
+) {
+    for entity in removed_sprites.iter() {
+        memberships.pending.remove(&entity);
+
+        if let Some(group_id) = memberships.groups.remove(&entity) {
+            if let Ok(mut group) = groups.get_mut(group_id) {
+                group.entities.remove(&entity);
+            }
+        }
+    }
+
+    let pending = std::mem::take(&mut memberships.pending);
+
+    for entity in changed_sprites.iter().chain(pending) {
+        let Ok(sprite) = sprites.get(entity) else {
+            continue;
+        };
+        if memberships.groups.get(&entity) == Some(&sprite.group_id) {
+            continue;
+        }
+
+        // Leave the previous group right away, even if the new one doesn't exist (yet)
+        if let Some(previous_group_id) = memberships.groups.remove(&entity) {
+            if let Ok(mut previous_group) = groups.get_mut(previous_group_id) {
+                previous_group.entities.remove(&entity);
+            }
+        }
+
+        let Ok(mut group) = groups.get_mut(sprite.group_id) else {
+            memberships.pending.insert(entity);
+            continue;
+        };
+        // The entity might have been inserted manually already
+        if !group.entities.contains(&entity) {
+            group.entities.insert(entity);
+        }
+        memberships.groups.insert(entity, sprite.group_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+
+    use super::update_instancing_group_membership;
+    use crate::{InstancedSprite, SpriteInstancingGroup};
+
+    fn group_contains(app: &App, group_id: Entity, entity: Entity) -> bool {
+        app.world
+            .get::<SpriteInstancingGroup>(group_id)
+            .unwrap()
+            .entities
+            .contains(&entity)
+    }
+
+    #[test]
+    fn sprite_leaves_group_for_missing_group() {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .add_system(update_instancing_group_membership);
+
+        let group_a = app.world.spawn(SpriteInstancingGroup::default()).id();
+        let group_b = app.world.spawn_empty().id();
+        let sprite = app
+            .world
+            .spawn(InstancedSprite {
+                group_id: group_a,
+                ..default()
+            })
+            .id();
+        app.update();
+        assert!(group_contains(&app, group_a, sprite));
+
+        app.world
+            .get_mut::<InstancedSprite>(sprite)
+            .unwrap()
+            .group_id = group_b;
+        app.update();
+        assert!(!group_contains(&app, group_a, sprite));
+
+        // Joins the group once it's created
+        app.world
+            .entity_mut(group_b)
+            .insert(SpriteInstancingGroup::default());
+        app.update();
+        assert!(group_contains(&app, group_b, sprite));
+        assert!(!group_contains(&app, group_a, sprite));
+
+        // Detached sprites don't belong to any group
+        app.world
+            .get_mut::<InstancedSprite>(sprite)
+            .unwrap()
+            .group_id = Entity::PLACEHOLDER;
+        app.update();
+        assert!(!group_contains(&app, group_b, sprite));
+    }
+}
@@ -2,30 +2,57 @@ use bevy::{
     core_pipeline::core_2d::Transparent2d,
     prelude::*,
     render::{
-        mesh::{GpuBufferInfo, GpuMesh, MeshVertexAttribute},
+        main_graph,
+        mesh::{GpuBufferInfo, GpuMesh, Indices},
+        render_asset::RenderAssets,
+        render_graph::RenderGraph,
         render_phase::AddRenderCommand,
         render_resource::{
-            BindGroup, BufferInitDescriptor, BufferUsages, PrimitiveTopology,
-            SpecializedMeshPipelines, VertexFormat,
+            BindGroup, BufferInitDescriptor, BufferUsages, IndexFormat, PrimitiveTopology,
+            SpecializedMeshPipelines,
         },
         renderer::RenderDevice,
         RenderApp, RenderSet,
     },
+    sprite::Anchor,
     utils::HashSet,
 };
 use bytemuck::{Pod, Zeroable};
 use extract::ExtractedComponentCache;
 
 use self::{
+    atlas::{build_spritesheet_texture_arrays, sync_texture_atlas_spritesheets},
+    culling::{
+        prepare_gpu_culling, GpuInstanceCulling, InstanceCullingNode, InstanceCullingPipeline,
+    },
     draw::DrawSpritesInstancedCommands,
-    extract::extract_instancing_groups,
-    pipeline::InstancedSpritePipeline,
-    prepare::{prepare_instanced_spritesheets, queue_instanced_sprites},
-    shader::{INSTANCED_ENTITY_SHADER, INSTANCED_ENTITY_SHADER_HANDLE},
+    extract::{extract_camera_render_layers, extract_instancing_groups},
+    group::{prune_instancing_groups, update_instancing_group_membership},
+    pipeline::{InstancedSpritePipeline, InstancingCapabilities},
+    prepare::{prepare_instanced_spritesheets, queue_instanced_sprites, SpritesheetBindGroupCache},
+    shader::{
+        INSTANCED_ENTITY_SHADER, INSTANCED_ENTITY_SHADER_HANDLE, INSTANCED_SPRITE_IMPORT_SHADER,
+        INSTANCED_SPRITE_IMPORT_SHADER_HANDLE, INSTANCE_CULLING_SHADER,
+        INSTANCE_CULLING_SHADER_HANDLE, INSTANCE_DATA_IMPORT_SHADER,
+        INSTANCE_DATA_IMPORT_SHADER_HANDLE,
+    },
 };
 
+pub use self::{
+    material::{
+        InstancedSpriteMaterial, InstancedSpriteMaterialKey, InstancedSpriteMaterialPipeline,
+        InstancedSpriteMaterialPlugin, PreparedInstancedSpriteMaterial,
+        RenderInstancedSpriteMaterials, SetInstancedSpriteMaterialBindGroup,
+    },
+    pipeline::{BlendModeKey, InstancedSpritePipelineKey},
+};
+
+mod atlas;
+mod culling;
 mod draw;
 mod extract;
+mod group;
+mod material;
 mod pipeline;
 mod prepare;
 mod shader;
@@ -36,14 +63,50 @@ pub struct InstancedSpriteRenderPlugin;
 pub struct InstancedSprite {
     pub texture_index: u32,
     pub group_id: Entity,
+    // Tint multiplied into the spritesheet texel color, including alpha
+    pub color: Color,
+    pub flip_x: bool,
+    pub flip_y: bool,
+    // Point of the sprite placed at its translation
+    pub anchor: Anchor,
+    // Overrides the size given by the group's `size_mode`
+    pub custom_size: Option<Vec2>,
 }
 
-#[derive(Component)]
+// Frame-flipping animation evaluated on the GPU, overrides `InstancedSprite::texture_index`
+#[derive(Component, Clone, Copy, Debug)]
+pub struct InstancedSpriteAnimation {
+    pub first_index: u32,
+    pub frame_count: u32,
+    // Frames per second
+    pub fps: f32,
+    pub mode: AnimationMode,
+    // Value of `Time::elapsed_seconds_wrapped()` at which the animation starts
+    pub start_time: f32,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AnimationMode {
+    #[default]
+    Loop = 0,
+    // Stops at the last frame
+    Once = 1,
+    // Plays forward, then backward
+    PingPong = 2,
+}
+
+#[derive(Component, Default)]
 pub struct InstancedSpritesheet {
     // Width of the spritesheet in tiles
     pub width_tiles: u32,
     pub height_tiles: u32,
     pub image: Handle<Image>,
+    // Pixel rectangles of the tiles of a non-uniform (packed) spritesheet, indexed by
+    // `texture_index`. The `width_tiles` x `height_tiles` grid is used when empty.
+    pub tile_rects: Vec<Rect>,
+    // Copy the tiles into layers of a texture array, so they can be filtered without bleeding
+    // into their neighbors. Requires equally sized tiles.
+    pub texture_array: bool,
 }
 
 #[derive(Component)]
@@ -56,17 +119,181 @@ pub struct InstancedSpriteMesh {
     quad: GpuMesh,
 }
 
+impl InstancedSpriteMesh {
+    // Mesh drawn for a group, `None` until a custom mesh is prepared
+    fn get<'a>(
+        &'a self,
+        mesh: Option<&Handle<Mesh>>,
+        meshes: &'a RenderAssets<Mesh>,
+    ) -> Option<&'a GpuMesh> {
+        match mesh {
+            Some(mesh) => meshes.get(mesh),
+            None => Some(&self.quad),
+        }
+    }
+}
+
 #[derive(Component)]
 pub struct SpriteInstancingGroup {
     pub entities: HashSet<Entity>,
+    // Skip instances outside of every active camera's view when uploading. Static groups are
+    // only culled with `gpu_culling`.
+    pub culling: bool,
+    // Only re-upload instance data when a member's transform or sprite, or the group itself,
+    // has changed. All members are uploaded, as culling them would need a re-upload whenever a
+    // camera moves.
+    pub is_static: bool,
+    // Depth of the whole group when sorted against other 2D items, same as a sprite's
+    // translation z
+    pub z_layer: f32,
+    // Order in which the group's instances are drawn
+    pub sort_mode: InstanceSortMode,
+    pub blend_mode: SpriteBlendMode,
+    // Size of sprites without a `custom_size`, before applying their transform's scale
+    pub size_mode: SpriteSizeMode,
+    // Shape drawn for each instance instead of the default quad. Needs positions and UVs, with
+    // the sprite spanning -0.5..0.5 and the tile 0..1 (v pointing down).
+    pub mesh: Option<Handle<Mesh>>,
+    // Cull in a compute pass and draw the visible instances indirectly, instead of culling while
+    // uploading. Moving cameras then don't cause static groups to be re-uploaded. Only applies
+    // to unsorted groups with `culling` set, on devices with compute shaders, indirect draws and
+    // vertex stage storage buffers.
+    pub gpu_culling: bool,
+    // Draw members without a `GlobalTransform` at their local `Transform`, for apps which don't
+    // use transform propagation. Otherwise such members are skipped.
+    pub local_transforms: bool,
 }
 
+// The 2D main pass has no depth buffer, so opaque and alpha-masked groups still rely on their
+// `z_layer` for ordering, but skip blending
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SpriteBlendMode {
+    Opaque,
+    // Discards texels with alpha below the cutoff
+    AlphaMask(f32),
+    #[default]
+    AlphaBlend,
+    Additive,
+    Multiply,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InstanceSortMode {
+    // Arbitrary order, cheapest to update
+    #[default]
+    Unsorted,
+    // Back-to-front by translation z
+    ByZ,
+    // Back-to-front by translation y (higher y first), for top-down games
+    ByY,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SpriteSizeMode {
+    // 1x1 world units, so the size is controlled by the transform's scale alone
+    #[default]
+    Unit,
+    // Pixel size of a spritesheet tile, same as Bevy's `Sprite`
+    TileSize,
+}
+
+// Mirrored by `SpriteInstanceData` in the shader when read from a storage buffer
 #[derive(Pod, Zeroable, Clone, Copy, Debug)]
 #[repr(C)]
 pub struct SpriteInstanceData {
     i_position: Vec3,
-    i_scale: Vec2,
+    // Column-major 2x2 matrix holding rotation, scale and sprite size
+    i_rotation_scale: [f32; 4],
     i_tex_index: u32,
+    i_color: [f32; 4],
+    // Animation frame count and mode
+    i_animation: [u32; 2],
+    // Animation FPS and start time
+    i_animation_time: [f32; 2],
+    i_anchor: Vec2,
+    i_flags: u32,
+}
+
+impl SpriteInstanceData {
+    const FLAG_FLIP_X: u32 = 1 << 0;
+    const FLAG_FLIP_Y: u32 = 1 << 1;
+}
+
+impl Default for InstancedSprite {
+    fn default() -> Self {
+        Self {
+            texture_index: 0,
+            group_id: Entity::PLACEHOLDER,
+            color: Color::WHITE,
+            flip_x: false,
+            flip_y: false,
+            anchor: Anchor::Center,
+            custom_size: None,
+        }
+    }
+}
+
+impl InstancedSpritesheet {
+    // Tiles of `tile_size` pixels laid out in a grid, same as `TextureAtlas::from_grid`
+    pub fn from_grid(
+        image: Handle<Image>,
+        tile_size: Vec2,
+        columns: usize,
+        rows: usize,
+        padding: Option<Vec2>,
+        offset: Option<Vec2>,
+    ) -> Self {
+        let padding = padding.unwrap_or_default();
+        let offset = offset.unwrap_or_default();
+        let mut tile_rects = Vec::with_capacity(columns * rows);
+
+        for y in 0..rows {
+            for x in 0..columns {
+                let min = (tile_size + padding) * Vec2::new(x as f32, y as f32) + offset;
+                tile_rects.push(Rect::from_corners(min, min + tile_size));
+            }
+        }
+
+        Self {
+            width_tiles: columns as u32,
+            height_tiles: rows as u32,
+            image,
+            tile_rects,
+            texture_array: false,
+        }
+    }
+
+    // Arbitrary tile rectangles in pixels, e.g. from a texture packer or `TextureAtlas::textures`
+    pub fn from_rects(image: Handle<Image>, tile_rects: Vec<Rect>) -> Self {
+        Self {
+            width_tiles: 0,
+            height_tiles: 0,
+            image,
+            tile_rects,
+            texture_array: false,
+        }
+    }
+
+    pub fn from_texture_atlas(atlas: &TextureAtlas) -> Self {
+        Self::from_rects(atlas.texture.clone(), atlas.textures.clone())
+    }
+}
+
+impl Default for SpriteInstancingGroup {
+    fn default() -> Self {
+        Self {
+            entities: HashSet::new(),
+            culling: true,
+            is_static: false,
+            z_layer: 0.0,
+            sort_mode: InstanceSortMode::Unsorted,
+            blend_mode: SpriteBlendMode::AlphaBlend,
+            size_mode: SpriteSizeMode::Unit,
+            mesh: None,
+            gpu_culling: false,
+            local_transforms: false,
+        }
+    }
 }
 
 impl Plugin for InstancedSpriteRenderPlugin {
@@ -76,21 +303,68 @@ impl Plugin for InstancedSpriteRenderPlugin {
             INSTANCED_ENTITY_SHADER_HANDLE,
             Shader::from_wgsl(INSTANCED_ENTITY_SHADER),
         );
+        shaders.set_untracked(
+            INSTANCED_SPRITE_IMPORT_SHADER_HANDLE,
+            Shader::from_wgsl(INSTANCED_SPRITE_IMPORT_SHADER),
+        );
+        shaders.set_untracked(
+            INSTANCE_DATA_IMPORT_SHADER_HANDLE,
+            Shader::from_wgsl(INSTANCE_DATA_IMPORT_SHADER),
+        );
+        shaders.set_untracked(
+            INSTANCE_CULLING_SHADER_HANDLE,
+            Shader::from_wgsl(INSTANCE_CULLING_SHADER),
+        );
+
+        app.add_systems(
+            (update_instancing_group_membership, prune_instancing_groups)
+                .chain()
+                .in_base_set(CoreSet::PostUpdate),
+        );
+        app.add_systems(
+            (
+                sync_texture_atlas_spritesheets,
+                build_spritesheet_texture_arrays,
+            )
+                .in_base_set(CoreSet::PostUpdate),
+        );
 
-        app.get_sub_app_mut(RenderApp)
-            .unwrap()
+        let render_app = app.get_sub_app_mut(RenderApp).unwrap();
+        render_app
             .add_render_command::<Transparent2d, DrawSpritesInstancedCommands>()
+            .init_resource::<InstancingCapabilities>()
             .init_resource::<InstancedSpritePipeline>()
             .init_resource::<SpecializedMeshPipelines<InstancedSpritePipeline>>()
             .init_resource::<ExtractedComponentCache>()
+            .init_resource::<SpritesheetBindGroupCache>()
             .add_system(
                 setup_entity_instancing_mesh
                     .in_schedule(ExtractSchedule)
                     .run_if(not(resource_exists::<InstancedSpriteMesh>())),
             )
             .add_system(extract_instancing_groups.in_schedule(ExtractSchedule))
+            .add_system(extract_camera_render_layers.in_schedule(ExtractSchedule))
             .add_system(prepare_instanced_spritesheets.in_set(RenderSet::Prepare))
             .add_system(queue_instanced_sprites.in_set(RenderSet::Queue));
+
+        if render_app
+            .world
+            .resource::<InstancingCapabilities>()
+            .gpu_culling
+        {
+            render_app
+                .init_resource::<InstanceCullingPipeline>()
+                .init_resource::<GpuInstanceCulling>()
+                .add_system(
+                    prepare_gpu_culling
+                        .in_set(RenderSet::Prepare)
+                        .before(prepare_instanced_spritesheets),
+                );
+
+            let mut render_graph = render_app.world.resource_mut::<RenderGraph>();
+            render_graph.add_node(InstanceCullingNode::NAME, InstanceCullingNode);
+            render_graph.add_node_edge(InstanceCullingNode::NAME, main_graph::node::CAMERA_DRIVER);
+        }
     }
 
     fn name(&self) -> &'static str {
@@ -104,35 +378,28 @@ fn setup_entity_instancing_mesh(mut commands: Commands, device: Res<RenderDevice
         [0.5, -0.5, 0.0],  // bottom right
         [0.5, 0.5, 0.0],   // top right
         [-0.5, 0.5, 0.0],  // top left
-        [-0.5, 0.5, 0.0],  // top left
         [-0.5, -0.5, 0.0], // bottom left
-        [0.5, -0.5, 0.0],  // bottom right
-    ];
-    let uvs = vec![
-        [1.0, 1.0],
-        [1.0, 0.0],
-        [0.0, 0.0],
-        [0.0, 0.0],
-        [0.0, 1.0],
-        [1.0, 1.0],
     ];
+    let uvs = vec![[1.0, 1.0], [1.0, 0.0], [0.0, 0.0], [0.0, 1.0]];
 
-    mesh.insert_attribute(
-        MeshVertexAttribute::new("v_position", 0, VertexFormat::Float32x3),
-        positions,
-    );
-    mesh.insert_attribute(
-        MeshVertexAttribute::new("v_tex_coords", 1, VertexFormat::Float32x2),
-        uvs,
-    );
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+    mesh.set_indices(Some(Indices::U16(vec![0, 1, 2, 2, 3, 0])));
 
     let vertex_buffer = device.create_buffer_with_data(&BufferInitDescriptor {
         label: Some("Entity instancing quad mesh"),
         contents: &mesh.get_vertex_buffer_data(),
         usage: BufferUsages::VERTEX,
     });
-    let buffer_info = GpuBufferInfo::NonIndexed {
-        vertex_count: mesh.count_vertices() as u32,
+    let index_buffer = device.create_buffer_with_data(&BufferInitDescriptor {
+        label: Some("Entity instancing quad mesh indices"),
+        contents: mesh.get_index_buffer_bytes().unwrap(),
+        usage: BufferUsages::INDEX,
+    });
+    let buffer_info = GpuBufferInfo::Indexed {
+        buffer: index_buffer,
+        count: mesh.indices().unwrap().len() as u32,
+        index_format: IndexFormat::Uint16,
     };
     let layout = mesh.get_mesh_vertex_buffer_layout();
     let primitive_topology = mesh.primitive_topology();
@@ -0,0 +1,444 @@
+use std::{hash::Hash, marker::PhantomData};
+
+use bevy::{
+    core_pipeline::core_2d::Transparent2d,
+    ecs::{
+        query::ROQueryItem,
+        system::{
+            lifetimeless::{Read, SRes},
+            SystemParamItem,
+        },
+    },
+    prelude::*,
+    reflect::TypeUuid,
+    render::{
+        mesh::MeshVertexBufferLayout,
+        render_asset::{PrepareAssetSet, RenderAssets},
+        render_phase::{
+            AddRenderCommand, DrawFunctions, PhaseItem, RenderCommand, RenderCommandResult,
+            RenderPhase, SetItemPipeline, TrackedRenderPass,
+        },
+        render_resource::{
+            AsBindGroup, AsBindGroupError, BindGroup, BindGroupLayout, OwnedBindingResource,
+            PipelineCache, RenderPipelineDescriptor, ShaderRef, SpecializedMeshPipeline,
+            SpecializedMeshPipelineError, SpecializedMeshPipelines,
+        },
+        renderer::RenderDevice,
+        texture::FallbackImage,
+        view::{ExtractedView, RenderLayers},
+        Extract, RenderApp, RenderSet,
+    },
+    sprite::{Mesh2dPipelineKey, SetMesh2dViewBindGroup},
+    utils::{FloatOrd, HashMap, HashSet},
+};
+
+use super::{
+    draw::DrawSpritesInstanced,
+    extract::{
+        ExtractedInstancedSpritesheet, ExtractedSpriteInstancingBuffer,
+        ExtractedSpriteInstancingGroup,
+    },
+    pipeline::{InstancedSpritePipeline, InstancedSpritePipelineKey},
+    prepare::{group_pipeline_key, specialize_group_pipeline},
+    InstancedSpriteMesh, InstancedSpritesheetBindGroup, SpriteInstancingGroup,
+};
+
+// A custom shader and bind group for instancing groups, similar to Bevy's `Material2d`.
+//
+// A group uses the material when its entity has a `Handle<M>`. The material's bind group is
+// bound at index 2, shaders can `#import bevy_sprite_instancing::instanced_sprite` to get the
+// spritesheet bindings, vertex types and helpers used by the default shader.
+pub trait InstancedSpriteMaterial:
+    AsBindGroup + Send + Sync + Clone + TypeUuid + Sized + 'static
+{
+    // ShaderRef::Default uses the default instanced sprite vertex shader
+    fn vertex_shader() -> ShaderRef {
+        ShaderRef::Default
+    }
+
+    // ShaderRef::Default uses the default instanced sprite fragment shader
+    fn fragment_shader() -> ShaderRef {
+        ShaderRef::Default
+    }
+
+    #[allow(unused_variables)]
+    #[inline]
+    fn specialize(
+        descriptor: &mut RenderPipelineDescriptor,
+        layout: &MeshVertexBufferLayout,
+        key: InstancedSpriteMaterialKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        Ok(())
+    }
+}
+
+// Has to be added after `InstancedSpriteRenderPlugin`
+pub struct InstancedSpriteMaterialPlugin<M: InstancedSpriteMaterial>(PhantomData<M>);
+
+// Marks the render entities of groups drawn with a material instead of the default shader
+#[derive(Component)]
+pub(super) struct HasInstancedSpriteMaterial;
+
+#[derive(Resource)]
+pub struct InstancedSpriteMaterialPipeline<M: InstancedSpriteMaterial> {
+    base_pipeline: InstancedSpritePipeline,
+    pub material_layout: BindGroupLayout,
+    pub vertex_shader: Option<Handle<Shader>>,
+    pub fragment_shader: Option<Handle<Shader>>,
+    marker: PhantomData<M>,
+}
+
+pub struct InstancedSpriteMaterialKey<M: InstancedSpriteMaterial> {
+    pub base_key: InstancedSpritePipelineKey,
+    pub bind_group_data: M::Data,
+}
+
+pub struct PreparedInstancedSpriteMaterial<M: InstancedSpriteMaterial> {
+    pub bindings: Vec<OwnedBindingResource>,
+    pub bind_group: BindGroup,
+    pub key: M::Data,
+}
+
+#[derive(Resource, Deref, DerefMut)]
+pub struct RenderInstancedSpriteMaterials<M: InstancedSpriteMaterial>(
+    HashMap<Handle<M>, PreparedInstancedSpriteMaterial<M>>,
+);
+
+#[derive(Resource)]
+struct ExtractedInstancedSpriteMaterials<M: InstancedSpriteMaterial> {
+    extracted: Vec<(Handle<M>, M)>,
+    removed: Vec<Handle<M>>,
+}
+
+pub struct SetInstancedSpriteMaterialBindGroup<M: InstancedSpriteMaterial, const I: usize>(
+    PhantomData<M>,
+);
+
+type DrawSpritesInstancedMaterial<M> = (
+    SetItemPipeline,
+    // View uniform
+    SetMesh2dViewBindGroup<0>,
+    SetInstancedSpriteMaterialBindGroup<M, 2>,
+    DrawSpritesInstanced,
+);
+
+impl<M: InstancedSpriteMaterial> Default for InstancedSpriteMaterialPlugin<M> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<M: InstancedSpriteMaterial> Plugin for InstancedSpriteMaterialPlugin<M>
+where
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
+    fn build(&self, app: &mut App) {
+        app.add_asset::<M>();
+
+        if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app
+                .add_render_command::<Transparent2d, DrawSpritesInstancedMaterial<M>>()
+                .init_resource::<InstancedSpriteMaterialPipeline<M>>()
+                .init_resource::<ExtractedInstancedSpriteMaterials<M>>()
+                .init_resource::<RenderInstancedSpriteMaterials<M>>()
+                .init_resource::<SpecializedMeshPipelines<InstancedSpriteMaterialPipeline<M>>>()
+                .add_systems(
+                    (
+                        extract_instanced_sprite_materials::<M>,
+                        extract_instancing_group_materials::<M>,
+                    )
+                        .in_schedule(ExtractSchedule),
+                )
+                .add_system(
+                    prepare_instanced_sprite_materials::<M>
+                        .in_set(RenderSet::Prepare)
+                        .after(PrepareAssetSet::PreAssetPrepare),
+                )
+                .add_system(queue_instanced_sprite_materials::<M>.in_set(RenderSet::Queue));
+        }
+    }
+}
+
+impl<M: InstancedSpriteMaterial> FromWorld for InstancedSpriteMaterialPipeline<M> {
+    fn from_world(world: &mut World) -> Self {
+        let asset_server = world.resource::<AssetServer>();
+        let render_device = world.resource::<RenderDevice>();
+        let material_layout = M::bind_group_layout(render_device);
+
+        let load_shader = |shader| match shader {
+            ShaderRef::Default => None,
+            ShaderRef::Handle(handle) => Some(handle),
+            ShaderRef::Path(path) => Some(asset_server.load(path)),
+        };
+
+        Self {
+            base_pipeline: world.resource::<InstancedSpritePipeline>().clone(),
+            material_layout,
+            vertex_shader: load_shader(M::vertex_shader()),
+            fragment_shader: load_shader(M::fragment_shader()),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<M: InstancedSpriteMaterial> SpecializedMeshPipeline for InstancedSpriteMaterialPipeline<M>
+where
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
+    type Key = InstancedSpriteMaterialKey<M>;
+
+    fn specialize(
+        &self,
+        key: Self::Key,
+        layout: &MeshVertexBufferLayout,
+    ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
+        let mut descriptor = self.base_pipeline.specialize(key.base_key, layout)?;
+
+        if let Some(vertex_shader) = &self.vertex_shader {
+            descriptor.vertex.shader = vertex_shader.clone();
+        }
+        if let Some(fragment_shader) = &self.fragment_shader {
+            descriptor.fragment.as_mut().unwrap().shader = fragment_shader.clone();
+        }
+        descriptor.layout.push(self.material_layout.clone());
+
+        M::specialize(&mut descriptor, layout, key)?;
+        Ok(descriptor)
+    }
+}
+
+impl<M: InstancedSpriteMaterial> Clone for InstancedSpriteMaterialKey<M>
+where
+    M::Data: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            base_key: self.base_key,
+            bind_group_data: self.bind_group_data.clone(),
+        }
+    }
+}
+
+impl<M: InstancedSpriteMaterial> PartialEq for InstancedSpriteMaterialKey<M>
+where
+    M::Data: PartialEq,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.base_key == other.base_key && self.bind_group_data == other.bind_group_data
+    }
+}
+
+impl<M: InstancedSpriteMaterial> Eq for InstancedSpriteMaterialKey<M> where M::Data: PartialEq {}
+
+impl<M: InstancedSpriteMaterial> Hash for InstancedSpriteMaterialKey<M>
+where
+    M::Data: Hash,
+{
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.base_key.hash(state);
+        self.bind_group_data.hash(state);
+    }
+}
+
+impl<M: InstancedSpriteMaterial> Default for RenderInstancedSpriteMaterials<M> {
+    fn default() -> Self {
+        Self(HashMap::default())
+    }
+}
+
+impl<M: InstancedSpriteMaterial> Default for ExtractedInstancedSpriteMaterials<M> {
+    fn default() -> Self {
+        Self {
+            extracted: vec![],
+            removed: vec![],
+        }
+    }
+}
+
+impl<P: PhaseItem, M: InstancedSpriteMaterial, const I: usize> RenderCommand<P>
+    for SetInstancedSpriteMaterialBindGroup<M, I>
+{
+    type Param = SRes<RenderInstancedSpriteMaterials<M>>;
+    type ViewWorldQuery = ();
+    type ItemWorldQuery = Read<Handle<M>>;
+
+    fn render<'w>(
+        _item: &P,
+        _view: (),
+        material_handle: ROQueryItem<'_, Self::ItemWorldQuery>,
+        materials: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let Some(material) = materials.into_inner().get(material_handle) else {
+            return RenderCommandResult::Failure;
+        };
+
+        pass.set_bind_group(I, &material.bind_group, &[]);
+        RenderCommandResult::Success
+    }
+}
+
+fn extract_instanced_sprite_materials<M: InstancedSpriteMaterial>(
+    mut commands: Commands,
+    mut events: Extract<EventReader<AssetEvent<M>>>,
+    assets: Extract<Res<Assets<M>>>,
+) {
+    let mut changed_assets = HashSet::new();
+    let mut removed = vec![];
+
+    for event in events.iter() {
+        match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+                changed_assets.insert(handle.clone_weak());
+            }
+            AssetEvent::Removed { handle } => {
+                changed_assets.remove(handle);
+                removed.push(handle.clone_weak());
+            }
+        }
+    }
+
+    let extracted = changed_assets
+        .into_iter()
+        .filter_map(|handle| {
+            let asset = assets.get(&handle)?.clone();
+            Some((handle, asset))
+        })
+        .collect();
+
+    commands.insert_resource(ExtractedInstancedSpriteMaterials { extracted, removed });
+}
+
+#[allow(clippy::type_complexity)]
+fn extract_instancing_group_materials<M: InstancedSpriteMaterial>(
+    mut commands: Commands,
+    instancing_groups: Extract<Query<(Entity, &Handle<M>), With<SpriteInstancingGroup>>>,
+) {
+    for (id, material) in &instancing_groups {
+        commands
+            .get_or_spawn(id)
+            .insert((material.clone_weak(), HasInstancedSpriteMaterial));
+    }
+}
+
+fn prepare_instanced_sprite_materials<M: InstancedSpriteMaterial>(
+    mut prepare_next_frame: Local<Vec<(Handle<M>, M)>>,
+    mut extracted_materials: ResMut<ExtractedInstancedSpriteMaterials<M>>,
+    mut render_materials: ResMut<RenderInstancedSpriteMaterials<M>>,
+    render_device: Res<RenderDevice>,
+    images: Res<RenderAssets<Image>>,
+    fallback_image: Res<FallbackImage>,
+    pipeline: Res<InstancedSpriteMaterialPipeline<M>>,
+) {
+    for removed in std::mem::take(&mut extracted_materials.removed) {
+        render_materials.remove(&removed);
+    }
+
+    let queued_materials = std::mem::take(&mut *prepare_next_frame);
+    let extracted_materials = std::mem::take(&mut extracted_materials.extracted);
+
+    for (handle, material) in queued_materials.into_iter().chain(extracted_materials) {
+        match material.as_bind_group(
+            &pipeline.material_layout,
+            &render_device,
+            &images,
+            &fallback_image,
+        ) {
+            Ok(prepared) => {
+                render_materials.insert(
+                    handle,
+                    PreparedInstancedSpriteMaterial {
+                        bindings: prepared.bindings,
+                        bind_group: prepared.bind_group,
+                        key: prepared.data,
+                    },
+                );
+            }
+            // Images used by the material aren't loaded yet
+            Err(AsBindGroupError::RetryNextUpdate) => {
+                prepare_next_frame.push((handle, material));
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn queue_instanced_sprite_materials<M: InstancedSpriteMaterial>(
+    transparent_draw_functions: Res<DrawFunctions<Transparent2d>>,
+    material_pipeline: Res<InstancedSpriteMaterialPipeline<M>>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<InstancedSpriteMaterialPipeline<M>>>,
+    pipeline_cache: Res<PipelineCache>,
+    msaa: Res<Msaa>,
+    entity_instancing_mesh: Res<InstancedSpriteMesh>,
+    meshes: Res<RenderAssets<Mesh>>,
+    render_materials: Res<RenderInstancedSpriteMaterials<M>>,
+    entity_instancing_groups: Query<
+        (
+            Entity,
+            &Handle<M>,
+            &ExtractedSpriteInstancingGroup,
+            &ExtractedInstancedSpritesheet,
+            &ExtractedSpriteInstancingBuffer,
+        ),
+        With<InstancedSpritesheetBindGroup>,
+    >,
+    mut views: Query<(
+        &mut RenderPhase<Transparent2d>,
+        &ExtractedView,
+        Option<&RenderLayers>,
+    )>,
+) where
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
+    for (mut transparent_phase, view, view_layers) in &mut views {
+        let view_layers = view_layers.copied().unwrap_or_default();
+
+        let draw_function = transparent_draw_functions
+            .read()
+            .id::<DrawSpritesInstancedMaterial<M>>();
+
+        let view_key = Mesh2dPipelineKey::from_msaa_samples(msaa.samples())
+            | Mesh2dPipelineKey::from_hdr(view.hdr);
+
+        for (entity, material_handle, group, spritesheet, instancing_buffer) in
+            &entity_instancing_groups
+        {
+            let Some(material) = render_materials.get(material_handle) else {
+                continue;
+            };
+            let Some((base_key, layout)) = group_pipeline_key(
+                view_key,
+                &view_layers,
+                group,
+                spritesheet,
+                instancing_buffer,
+                &entity_instancing_mesh,
+                &meshes,
+            ) else {
+                continue;
+            };
+
+            let key = InstancedSpriteMaterialKey {
+                base_key,
+                bind_group_data: material.key.clone(),
+            };
+            let Some(pipeline) = specialize_group_pipeline(
+                &mut pipelines,
+                &pipeline_cache,
+                &material_pipeline,
+                key,
+                layout,
+            ) else {
+                continue;
+            };
+
+            transparent_phase.add(Transparent2d {
+                sort_key: FloatOrd(group.z_layer),
+                entity,
+                pipeline,
+                draw_function,
+                batch_range: None,
+            });
+        }
+    }
+}
@@ -1,114 +1,384 @@
 use std::mem::size_of;
 
 use bevy::{
-    prelude::{FromWorld, Resource, World},
+    prelude::{FromWorld, Mesh, Resource, World},
     render::{
         mesh::MeshVertexBufferLayout,
         render_resource::{
             BindGroupLayout, BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingType,
-            BufferBindingType, RenderPipelineDescriptor, SamplerBindingType, ShaderStages,
-            SpecializedMeshPipeline, SpecializedMeshPipelineError, TextureSampleType,
-            TextureViewDimension, VertexAttribute, VertexBufferLayout, VertexFormat,
-            VertexStepMode,
+            BlendComponent, BlendFactor, BlendOperation, BlendState, BufferBindingType,
+            RenderPipelineDescriptor, SamplerBindingType, ShaderStages, SpecializedMeshPipeline,
+            SpecializedMeshPipelineError, TextureSampleType, TextureViewDimension, VertexAttribute,
+            VertexBufferLayout, VertexFormat, VertexStepMode,
         },
-        renderer::RenderDevice,
+        renderer::{RenderAdapter, RenderDevice},
     },
     sprite::{Mesh2dPipeline, Mesh2dPipelineKey},
 };
 use field_offset::offset_of;
+use wgpu::DownlevelFlags;
 
-use super::{shader::INSTANCED_ENTITY_SHADER_HANDLE, SpriteInstanceData};
+use super::{shader::INSTANCED_ENTITY_SHADER_HANDLE, SpriteBlendMode, SpriteInstanceData};
 
-#[derive(Resource)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BlendModeKey {
+    Opaque,
+    AlphaMask,
+    AlphaBlend,
+    Additive,
+    Multiply,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct InstancedSpritePipelineKey {
+    pub mesh_key: Mesh2dPipelineKey,
+    pub blend_mode: BlendModeKey,
+    // Spritesheet tiles are layers of a texture array
+    pub texture_array: bool,
+}
+
+#[derive(Resource, Clone)]
 pub(super) struct InstancedSpritePipeline {
     pub spritesheet_uniform_layout: BindGroupLayout,
+    pub spritesheet_array_layout: BindGroupLayout,
     pub mesh2d_pipeline: Mesh2dPipeline,
+    // Tile rectangles are read from a storage buffer instead of a fixed-size uniform array
+    pub storage_rects: bool,
+    // Instance data is read from a storage buffer by instance index instead of being passed as
+    // vertex attributes
+    pub storage_instances: bool,
 }
 
 impl FromWorld for InstancedSpritePipeline {
     fn from_world(world: &mut World) -> Self {
+        let InstancingCapabilities {
+            storage_rects,
+            storage_instances,
+            ..
+        } = *world.resource::<InstancingCapabilities>();
         let device = world.resource::<RenderDevice>();
-        let descriptor = BindGroupLayoutDescriptor {
-            label: Some("Instanced entity spritesheet bind group layout"),
-            entries: &[
-                BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: ShaderStages::FRAGMENT,
-                    ty: BindingType::Texture {
-                        sample_type: TextureSampleType::Float { filterable: true },
-                        view_dimension: TextureViewDimension::D2,
-                        multisampled: false,
-                    },
-                    count: None,
-                },
-                BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: ShaderStages::FRAGMENT,
-                    ty: BindingType::Sampler(SamplerBindingType::Filtering),
-                    count: None,
-                },
-                BindGroupLayoutEntry {
-                    binding: 2,
-                    visibility: ShaderStages::FRAGMENT,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-            ],
-        };
 
-        let spritesheet_uniform_layout = device.create_bind_group_layout(&descriptor);
+        let spritesheet_uniform_layout =
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("Instanced entity spritesheet bind group layout"),
+                entries: &spritesheet_layout_entries(
+                    TextureViewDimension::D2,
+                    storage_rects,
+                    storage_instances,
+                ),
+            });
+        let spritesheet_array_layout =
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("Instanced entity texture array spritesheet bind group layout"),
+                entries: &spritesheet_layout_entries(
+                    TextureViewDimension::D2Array,
+                    storage_rects,
+                    storage_instances,
+                ),
+            });
 
         Self {
             spritesheet_uniform_layout,
+            spritesheet_array_layout,
             mesh2d_pipeline: Mesh2dPipeline::from_world(world),
+            storage_rects,
+            storage_instances,
+        }
+    }
+}
+
+// Without storage buffers (e.g. on WebGL2), tile rectangles are uploaded as a uniform array of
+// this many rectangles
+pub(super) const MAX_UNIFORM_TILE_RECTS: usize = 1024;
+
+// Storage buffers aren't available everywhere (e.g. not on WebGL2, and some GLES devices only have
+// them in compute shaders). Tile rectangles fall back to a uniform array and instance data to
+// vertex attributes there.
+#[derive(Resource, Clone, Copy)]
+pub(super) struct InstancingCapabilities {
+    // Tile rectangles are read from a storage buffer in the fragment stage
+    pub storage_rects: bool,
+    // Instance data is read from a storage buffer in the vertex stage
+    pub storage_instances: bool,
+    // Instances can be culled in a compute pass and drawn indirectly
+    pub gpu_culling: bool,
+}
+
+impl FromWorld for InstancingCapabilities {
+    fn from_world(world: &mut World) -> Self {
+        let storage_buffers = world
+            .resource::<RenderDevice>()
+            .limits()
+            .max_storage_buffers_per_shader_stage
+            > 0;
+        let downlevel_flags = world
+            .resource::<RenderAdapter>()
+            .get_downlevel_capabilities()
+            .flags;
+
+        let storage_instances =
+            storage_buffers && downlevel_flags.contains(DownlevelFlags::VERTEX_STORAGE);
+
+        Self {
+            storage_rects: storage_buffers
+                && downlevel_flags.contains(DownlevelFlags::FRAGMENT_STORAGE),
+            storage_instances,
+            gpu_culling: storage_instances
+                && downlevel_flags
+                    .contains(DownlevelFlags::COMPUTE_SHADERS | DownlevelFlags::INDIRECT_EXECUTION),
+        }
+    }
+}
+
+// Spritesheet bind group layout, differing in the dimension of the texture, the kind of buffer
+// holding the tile rectangles and whether it holds the instance buffer
+fn spritesheet_layout_entries(
+    view_dimension: TextureViewDimension,
+    storage_rects: bool,
+    storage_instances: bool,
+) -> Vec<BindGroupLayoutEntry> {
+    let rects_buffer_type = if storage_rects {
+        BufferBindingType::Storage { read_only: true }
+    } else {
+        BufferBindingType::Uniform
+    };
+
+    let mut entries = vec![
+        BindGroupLayoutEntry {
+            binding: 0,
+            visibility: ShaderStages::FRAGMENT,
+            ty: BindingType::Texture {
+                sample_type: TextureSampleType::Float { filterable: true },
+                view_dimension,
+                multisampled: false,
+            },
+            count: None,
+        },
+        BindGroupLayoutEntry {
+            binding: 1,
+            visibility: ShaderStages::FRAGMENT,
+            ty: BindingType::Sampler(SamplerBindingType::Filtering),
+            count: None,
+        },
+        BindGroupLayoutEntry {
+            binding: 2,
+            visibility: ShaderStages::FRAGMENT,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        },
+        // Group uniform
+        BindGroupLayoutEntry {
+            binding: 3,
+            visibility: ShaderStages::VERTEX_FRAGMENT,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        },
+        // Tile rectangles
+        BindGroupLayoutEntry {
+            binding: 4,
+            visibility: ShaderStages::FRAGMENT,
+            ty: BindingType::Buffer {
+                ty: rects_buffer_type,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        },
+    ];
+
+    if storage_instances {
+        entries.push(BindGroupLayoutEntry {
+            binding: 5,
+            visibility: ShaderStages::VERTEX,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        });
+    }
+
+    entries
+}
+
+impl From<SpriteBlendMode> for BlendModeKey {
+    fn from(blend_mode: SpriteBlendMode) -> Self {
+        match blend_mode {
+            SpriteBlendMode::Opaque => Self::Opaque,
+            SpriteBlendMode::AlphaMask(_) => Self::AlphaMask,
+            SpriteBlendMode::AlphaBlend => Self::AlphaBlend,
+            SpriteBlendMode::Additive => Self::Additive,
+            SpriteBlendMode::Multiply => Self::Multiply,
+        }
+    }
+}
+
+impl BlendModeKey {
+    fn blend_state(self) -> Option<BlendState> {
+        match self {
+            Self::Opaque | Self::AlphaMask => None,
+            Self::AlphaBlend => Some(BlendState::ALPHA_BLENDING),
+            Self::Additive => Some(BlendState {
+                color: BlendComponent {
+                    src_factor: BlendFactor::SrcAlpha,
+                    dst_factor: BlendFactor::One,
+                    operation: BlendOperation::Add,
+                },
+                alpha: BlendComponent {
+                    src_factor: BlendFactor::Zero,
+                    dst_factor: BlendFactor::One,
+                    operation: BlendOperation::Add,
+                },
+            }),
+            // The shader fades the color towards white by its alpha
+            Self::Multiply => Some(BlendState {
+                color: BlendComponent {
+                    src_factor: BlendFactor::Dst,
+                    dst_factor: BlendFactor::Zero,
+                    operation: BlendOperation::Add,
+                },
+                alpha: BlendComponent {
+                    src_factor: BlendFactor::Zero,
+                    dst_factor: BlendFactor::One,
+                    operation: BlendOperation::Add,
+                },
+            }),
+        }
+    }
+
+    fn shader_def(self) -> Option<&'static str> {
+        match self {
+            Self::Opaque => Some("BLEND_OPAQUE"),
+            Self::AlphaMask => Some("BLEND_ALPHA_MASK"),
+            Self::Multiply => Some("BLEND_MULTIPLY"),
+            Self::AlphaBlend | Self::Additive => None,
         }
     }
 }
 
 impl SpecializedMeshPipeline for InstancedSpritePipeline {
-    type Key = Mesh2dPipelineKey;
+    type Key = InstancedSpritePipelineKey;
 
     fn specialize(
         &self,
         key: Self::Key,
         layout: &MeshVertexBufferLayout,
     ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
-        let mut descriptor = self.mesh2d_pipeline.specialize(key, layout)?;
+        let mut descriptor = self.mesh2d_pipeline.specialize(key.mesh_key, layout)?;
+        // The instance attributes start right after these
+        descriptor.vertex.buffers[0] = layout.get_layout(&[
+            Mesh::ATTRIBUTE_POSITION.at_shader_location(0),
+            Mesh::ATTRIBUTE_UV_0.at_shader_location(1),
+        ])?;
         let shader = INSTANCED_ENTITY_SHADER_HANDLE.typed();
 
         descriptor.vertex.shader = shader.clone();
-        descriptor.fragment.as_mut().unwrap().shader = shader;
-
-        descriptor.vertex.buffers.push(VertexBufferLayout {
-            array_stride: size_of::<SpriteInstanceData>() as u64,
-            step_mode: VertexStepMode::Instance,
-            attributes: vec![
-                // i_position
-                VertexAttribute {
-                    format: VertexFormat::Float32x3,
-                    offset: offset_of!(SpriteInstanceData => i_position).get_byte_offset() as u64,
-                    shader_location: 2,
-                },
-                // i_scale
-                VertexAttribute {
-                    format: VertexFormat::Float32x2,
-                    offset: offset_of!(SpriteInstanceData => i_scale).get_byte_offset() as u64,
-                    shader_location: 3,
-                },
-                // i_tex_index
-                VertexAttribute {
-                    format: VertexFormat::Uint32,
-                    offset: offset_of!(SpriteInstanceData => i_tex_index).get_byte_offset() as u64,
-                    shader_location: 4,
-                },
-            ],
-        });
 
-        descriptor.layout[1] = self.spritesheet_uniform_layout.clone();
+        let fragment = descriptor.fragment.as_mut().unwrap();
+        fragment.shader = shader;
+        fragment.targets[0].as_mut().unwrap().blend = key.blend_mode.blend_state();
+        if let Some(shader_def) = key.blend_mode.shader_def() {
+            fragment.shader_defs.push(shader_def.into());
+        }
+        if key.texture_array {
+            fragment
+                .shader_defs
+                .push("SPRITESHEET_TEXTURE_ARRAY".into());
+            descriptor
+                .vertex
+                .shader_defs
+                .push("SPRITESHEET_TEXTURE_ARRAY".into());
+        }
+
+        if self.storage_rects {
+            fragment.shader_defs.push("STORAGE_TILE_RECTS".into());
+            descriptor
+                .vertex
+                .shader_defs
+                .push("STORAGE_TILE_RECTS".into());
+        }
+        if self.storage_instances {
+            fragment.shader_defs.push("STORAGE_INSTANCES".into());
+            descriptor
+                .vertex
+                .shader_defs
+                .push("STORAGE_INSTANCES".into());
+        } else {
+            descriptor.vertex.buffers.push(VertexBufferLayout {
+                array_stride: size_of::<SpriteInstanceData>() as u64,
+                step_mode: VertexStepMode::Instance,
+                attributes: vec![
+                    // i_position
+                    VertexAttribute {
+                        format: VertexFormat::Float32x3,
+                        offset: offset_of!(SpriteInstanceData => i_position).get_byte_offset()
+                            as u64,
+                        shader_location: 2,
+                    },
+                    // i_rotation_scale
+                    VertexAttribute {
+                        format: VertexFormat::Float32x4,
+                        offset: offset_of!(SpriteInstanceData => i_rotation_scale).get_byte_offset()
+                            as u64,
+                        shader_location: 3,
+                    },
+                    // i_tex_index
+                    VertexAttribute {
+                        format: VertexFormat::Uint32,
+                        offset: offset_of!(SpriteInstanceData => i_tex_index).get_byte_offset()
+                            as u64,
+                        shader_location: 4,
+                    },
+                    // i_color
+                    VertexAttribute {
+                        format: VertexFormat::Float32x4,
+                        offset: offset_of!(SpriteInstanceData => i_color).get_byte_offset() as u64,
+                        shader_location: 5,
+                    },
+                    // i_animation
+                    VertexAttribute {
+                        format: VertexFormat::Uint32x2,
+                        offset: offset_of!(SpriteInstanceData => i_animation).get_byte_offset()
+                            as u64,
+                        shader_location: 6,
+                    },
+                    // i_animation_time
+                    VertexAttribute {
+                        format: VertexFormat::Float32x2,
+                        offset: offset_of!(SpriteInstanceData => i_animation_time).get_byte_offset()
+                            as u64,
+                        shader_location: 7,
+                    },
+                    // i_anchor
+                    VertexAttribute {
+                        format: VertexFormat::Float32x2,
+                        offset: offset_of!(SpriteInstanceData => i_anchor).get_byte_offset() as u64,
+                        shader_location: 8,
+                    },
+                    // i_flags
+                    VertexAttribute {
+                        format: VertexFormat::Uint32,
+                        offset: offset_of!(SpriteInstanceData => i_flags).get_byte_offset() as u64,
+                        shader_location: 9,
+                    },
+                ],
+            });
+        }
+
+        descriptor.layout[1] = if key.texture_array {
+            self.spritesheet_array_layout.clone()
+        } else {
+            self.spritesheet_uniform_layout.clone()
+        };
 
         Ok(descriptor)
     }
@@ -2,67 +2,201 @@ use bevy::{
     core_pipeline::core_2d::Transparent2d,
     prelude::*,
     render::{
+        mesh::MeshVertexBufferLayout,
         render_asset::RenderAssets,
         render_phase::{DrawFunctions, RenderPhase},
         render_resource::{
-            BindGroupDescriptor, BindGroupEntry, BindingResource, PipelineCache,
-            SpecializedMeshPipelines,
+            BindGroup, BindGroupDescriptor, BindGroupEntry, BindingResource, BufferId,
+            CachedRenderPipelineId, PipelineCache, SamplerId, SpecializedMeshPipeline,
+            SpecializedMeshPipelines, TextureViewId,
         },
         renderer::RenderDevice,
-        view::ExtractedView,
+        view::{ExtractedView, RenderLayers},
     },
     sprite::Mesh2dPipelineKey,
-    utils::FloatOrd,
+    utils::{FloatOrd, HashMap},
 };
 
 use super::{
+    culling::GpuInstanceCulling,
     draw::DrawSpritesInstancedCommands,
-    extract::{ExtractedInstancedSpritesheet, ExtractedSpriteInstancingBuffer},
-    pipeline::InstancedSpritePipeline,
+    extract::{
+        ExtractedInstancedSpritesheet, ExtractedSpriteInstancingBuffer,
+        ExtractedSpriteInstancingGroup,
+    },
+    material::HasInstancedSpriteMaterial,
+    pipeline::{InstancedSpritePipeline, InstancedSpritePipelineKey},
     InstancedSpriteMesh, InstancedSpritesheetBindGroup,
 };
 
+// Spritesheet bind groups of the last frame, along with the resources they were created from
+#[derive(Resource, Default)]
+pub(super) struct SpritesheetBindGroupCache {
+    bind_groups: HashMap<Entity, (SpritesheetBindGroupKey, BindGroup)>,
+}
+
+#[derive(PartialEq, Eq)]
+struct SpritesheetBindGroupKey {
+    // A modified (or hot-reloaded) image gets a new texture view
+    texture_view: TextureViewId,
+    sampler: SamplerId,
+    size_buffer: BufferId,
+    rects_buffer: BufferId,
+    uniform_buffer: BufferId,
+    // Replaced when the instance buffer grows
+    instance_buffer: BufferId,
+}
+
 #[allow(clippy::too_many_arguments)]
 pub(super) fn prepare_instanced_spritesheets(
     mut commands: Commands,
     pipeline: Res<InstancedSpritePipeline>,
     images: Res<RenderAssets<Image>>,
     render_device: Res<RenderDevice>,
-    spritesheet_query: Query<(Entity, &ExtractedInstancedSpritesheet)>,
+    mut bind_group_cache: ResMut<SpritesheetBindGroupCache>,
+    gpu_culling: Option<Res<GpuInstanceCulling>>,
+    spritesheet_query: Query<(
+        Entity,
+        &ExtractedInstancedSpritesheet,
+        &ExtractedSpriteInstancingGroup,
+        &ExtractedSpriteInstancingBuffer,
+    )>,
 ) {
-    for (id, spritesheet) in &spritesheet_query {
+    let mut bind_groups = HashMap::new();
+
+    for (id, spritesheet, group, instancing_buffer) in &spritesheet_query {
         let Some(spritesheet_image) = images.get(&spritesheet.image) else {
             continue;
         };
+        // GPU-culled groups draw their culled instances
+        let instance_buffer = gpu_culling
+            .as_ref()
+            .and_then(|gpu_culling| gpu_culling.culled_instances(id))
+            .map_or(&instancing_buffer.device_buffer, |culled| {
+                &culled.culled_buffer
+            });
+
+        let key = SpritesheetBindGroupKey {
+            texture_view: spritesheet_image.texture_view.id(),
+            sampler: spritesheet_image.sampler.id(),
+            size_buffer: spritesheet.size_buffer.id(),
+            rects_buffer: spritesheet.rects_buffer.id(),
+            uniform_buffer: group.uniform_buffer.id(),
+            instance_buffer: instance_buffer.id(),
+        };
+        if let Some((cached_key, bind_group)) = bind_group_cache.bind_groups.remove(&id) {
+            if cached_key == key {
+                commands
+                    .get_or_spawn(id)
+                    .insert(InstancedSpritesheetBindGroup {
+                        bind_group: bind_group.clone(),
+                    });
+                bind_groups.insert(id, (key, bind_group));
+                continue;
+            }
+        }
+
+        let mut entries = vec![
+            BindGroupEntry {
+                binding: 0,
+                resource: BindingResource::TextureView(&spritesheet_image.texture_view),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: BindingResource::Sampler(&spritesheet_image.sampler),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: BindingResource::Buffer(
+                    spritesheet.size_buffer.as_entire_buffer_binding(),
+                ),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: BindingResource::Buffer(group.uniform_buffer.as_entire_buffer_binding()),
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: BindingResource::Buffer(
+                    spritesheet.rects_buffer.as_entire_buffer_binding(),
+                ),
+            },
+        ];
+        if pipeline.storage_instances {
+            entries.push(BindGroupEntry {
+                binding: 5,
+                resource: BindingResource::Buffer(instance_buffer.as_entire_buffer_binding()),
+            });
+        }
 
         let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
             label: Some("Instanced spritesheet bind group"),
-            layout: &pipeline.spritesheet_uniform_layout,
-            entries: &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: BindingResource::TextureView(&spritesheet_image.texture_view),
-                },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: BindingResource::Sampler(&spritesheet_image.sampler),
-                },
-                BindGroupEntry {
-                    binding: 2,
-                    resource: BindingResource::Buffer(
-                        spritesheet.size_buffer.as_entire_buffer_binding(),
-                    ),
-                },
-            ],
+            layout: if spritesheet.texture_array {
+                &pipeline.spritesheet_array_layout
+            } else {
+                &pipeline.spritesheet_uniform_layout
+            },
+            entries: &entries,
         });
 
         commands
             .get_or_spawn(id)
-            .insert(InstancedSpritesheetBindGroup { bind_group });
+            .insert(InstancedSpritesheetBindGroup {
+                bind_group: bind_group.clone(),
+            });
+        bind_groups.insert(id, (key, bind_group));
     }
+
+    // Bind groups of groups which weren't prepared this frame are dropped
+    bind_group_cache.bind_groups = bind_groups;
 }
 
-#[allow(clippy::too_many_arguments)]
+// Pipeline key and mesh layout of a group drawn to a view, `None` when the group has nothing to
+// draw there
+pub(super) fn group_pipeline_key<'a>(
+    view_key: Mesh2dPipelineKey,
+    view_layers: &RenderLayers,
+    group: &ExtractedSpriteInstancingGroup,
+    spritesheet: &ExtractedInstancedSpritesheet,
+    instancing_buffer: &ExtractedSpriteInstancingBuffer,
+    instancing_mesh: &'a InstancedSpriteMesh,
+    meshes: &'a RenderAssets<Mesh>,
+) -> Option<(InstancedSpritePipelineKey, &'a MeshVertexBufferLayout)> {
+    // Empty (or completely culled) groups keep their buffer, but have nothing to draw
+    if !group.visible
+        || !view_layers.intersects(&group.render_layers)
+        || instancing_buffer.length == 0
+    {
+        return None;
+    }
+    let mesh = instancing_mesh.get(group.mesh.as_ref(), meshes)?;
+
+    let key = InstancedSpritePipelineKey {
+        mesh_key: view_key | Mesh2dPipelineKey::from_primitive_topology(mesh.primitive_topology),
+        blend_mode: group.blend_mode.into(),
+        texture_array: spritesheet.texture_array,
+    };
+    Some((key, &mesh.layout))
+}
+
+// Specialization errors are logged instead of panicking, the group is skipped then
+pub(super) fn specialize_group_pipeline<P: SpecializedMeshPipeline>(
+    pipelines: &mut SpecializedMeshPipelines<P>,
+    pipeline_cache: &PipelineCache,
+    pipeline: &P,
+    key: P::Key,
+    layout: &MeshVertexBufferLayout,
+) -> Option<CachedRenderPipelineId> {
+    match pipelines.specialize(pipeline_cache, pipeline, key, layout) {
+        Ok(pipeline) => Some(pipeline),
+        Err(error) => {
+            error!("{}", error);
+            None
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
 pub(super) fn queue_instanced_sprites(
     transparent_draw_functions: Res<DrawFunctions<Transparent2d>>,
     instanced_entity_pipeline: Res<InstancedSpritePipeline>,
@@ -70,35 +204,59 @@ pub(super) fn queue_instanced_sprites(
     pipeline_cache: Res<PipelineCache>,
     msaa: Res<Msaa>,
     entity_instancing_mesh: Res<InstancedSpriteMesh>,
+    meshes: Res<RenderAssets<Mesh>>,
     entity_instancing_groups: Query<
-        Entity,
+        (
+            Entity,
+            &ExtractedSpriteInstancingGroup,
+            &ExtractedInstancedSpritesheet,
+            &ExtractedSpriteInstancingBuffer,
+        ),
         (
             With<InstancedSpritesheetBindGroup>,
-            With<ExtractedSpriteInstancingBuffer>,
+            Without<HasInstancedSpriteMaterial>,
         ),
     >,
-    mut views: Query<(&mut RenderPhase<Transparent2d>, &ExtractedView)>,
+    mut views: Query<(
+        &mut RenderPhase<Transparent2d>,
+        &ExtractedView,
+        Option<&RenderLayers>,
+    )>,
 ) {
-    let layout = &entity_instancing_mesh.quad.layout;
+    for (mut transparent_phase, view, view_layers) in &mut views {
+        let view_layers = view_layers.copied().unwrap_or_default();
 
-    for (mut transparent_phase, view) in &mut views {
         let draw_function = transparent_draw_functions
             .read()
             .id::<DrawSpritesInstancedCommands>();
 
-        let key = Mesh2dPipelineKey::from_msaa_samples(msaa.samples())
-            | Mesh2dPipelineKey::from_hdr(view.hdr)
-            | Mesh2dPipelineKey::from_primitive_topology(
-                entity_instancing_mesh.quad.primitive_topology,
-            );
+        let view_key = Mesh2dPipelineKey::from_msaa_samples(msaa.samples())
+            | Mesh2dPipelineKey::from_hdr(view.hdr);
 
-        let pipeline = pipelines
-            .specialize(&pipeline_cache, &instanced_entity_pipeline, key, layout)
-            .unwrap();
+        for (entity, group, spritesheet, instancing_buffer) in &entity_instancing_groups {
+            let Some((key, layout)) = group_pipeline_key(
+                view_key,
+                &view_layers,
+                group,
+                spritesheet,
+                instancing_buffer,
+                &entity_instancing_mesh,
+                &meshes,
+            ) else {
+                continue;
+            };
+            let Some(pipeline) = specialize_group_pipeline(
+                &mut pipelines,
+                &pipeline_cache,
+                &instanced_entity_pipeline,
+                key,
+                layout,
+            ) else {
+                continue;
+            };
 
-        for entity in &entity_instancing_groups {
             transparent_phase.add(Transparent2d {
-                sort_key: FloatOrd(0.0),
+                sort_key: FloatOrd(group.z_layer),
                 entity,
                 pipeline,
                 draw_function,
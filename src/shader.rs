@@ -6,19 +6,103 @@ use bevy::{
 pub const INSTANCED_ENTITY_SHADER_HANDLE: HandleUntyped =
     HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 12344143414121);
 
-pub(super) const INSTANCED_ENTITY_SHADER: &str = r#"
-#import bevy_sprite::mesh2d_view_bindings
-#import bevy_sprite::mesh2d_bindings
+// Bindings, vertex types and helpers shared by the default shader and material shaders,
+// importable as `bevy_sprite_instancing::instanced_sprite`
+pub const INSTANCED_SPRITE_IMPORT_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 12344143414122);
+
+// Storage buffer layout of the instance data, importable as
+// `bevy_sprite_instancing::instance_data`
+pub const INSTANCE_DATA_IMPORT_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 12344143414123);
+
+pub(super) const INSTANCE_DATA_IMPORT_SHADER: &str = r#"
+#define_import_path bevy_sprite_instancing::instance_data
+
+// Same layout as `SpriteInstanceData`, which is tightly packed
+struct SpriteInstanceData {
+    position: array<f32, 3>,
+    rotation_scale: array<f32, 4>,
+    tex_index: u32,
+    color: array<f32, 4>,
+    animation: array<u32, 2>,
+    animation_time: array<f32, 2>,
+    anchor: array<f32, 2>,
+    flags: u32,
+};
+"#;
+
+pub(super) const INSTANCED_SPRITE_IMPORT_SHADER: &str = r#"
+#define_import_path bevy_sprite_instancing::instanced_sprite
 
-#import bevy_sprite::mesh2d_functions
+#import bevy_sprite::mesh2d_view_bindings
+#import bevy_sprite_instancing::instance_data
 
+#ifdef SPRITESHEET_TEXTURE_ARRAY
+// One layer per tile
+@group(1) @binding(0)
+var texture: texture_2d_array<f32>;
+#else
 @group(1) @binding(0)
 var texture: texture_2d<f32>;
+#endif
 @group(1) @binding(1)
 var texture_sampler: sampler;
+
+struct Spritesheet {
+    // Size of the grid in tiles
+    size_tiles: vec2<u32>,
+    // Number of tile rectangles, the grid is used when there are none
+    rect_count: u32,
+};
+
 @group(1) @binding(2)
-var<uniform> spritesheet_tile_size: vec2<u32>;
+var<uniform> spritesheet: Spritesheet;
+
+struct InstancingGroup {
+    alpha_cutoff: f32,
+    // Period after which `globals.time` wraps around
+    time_wrap_period: f32,
+};
+
+@group(1) @binding(3)
+var<uniform> instancing_group: InstancingGroup;
 
+// Min and max corners of each tile, in pixels
+#ifdef STORAGE_TILE_RECTS
+@group(1) @binding(4)
+var<storage> spritesheet_rects: array<vec4<f32>>;
+#else
+// Same length as `MAX_UNIFORM_TILE_RECTS`
+@group(1) @binding(4)
+var<uniform> spritesheet_rects: array<vec4<f32>, 1024>;
+#endif
+
+// Instance data, see `instanced_sprite_instance`
+struct SpriteInstance {
+    position: vec3<f32>,
+    rotation_scale: vec4<f32>,
+    tex_index: u32,
+    color: vec4<f32>,
+    // Frame count, mode
+    animation: vec2<u32>,
+    // FPS, start time
+    animation_time: vec2<f32>,
+    anchor: vec2<f32>,
+    // Bit 0: flip x, bit 1: flip y
+    flags: u32,
+};
+
+#ifdef STORAGE_INSTANCES
+@group(1) @binding(5)
+var<storage> instances: array<SpriteInstanceData>;
+
+struct Vertex {
+    @location(0) v_position: vec2<f32>,
+    @location(1) v_tex_coords: vec2<f32>,
+    @builtin(instance_index) instance_index: u32,
+};
+#else
 struct Vertex {
     // Per-vertex
     @location(0) v_position: vec2<f32>,
@@ -26,40 +110,247 @@ struct Vertex {
 
     // Per-instance
     @location(2) i_position: vec3<f32>,
-    @location(3) i_scale: vec2<f32>,
+    @location(3) i_rotation_scale: vec4<f32>,
     @location(4) i_tex_index: u32,
+    @location(5) i_color: vec4<f32>,
+    @location(6) i_animation: vec2<u32>,
+    @location(7) i_animation_time: vec2<f32>,
+    @location(8) i_anchor: vec2<f32>,
+    @location(9) i_flags: u32,
 };
+#endif
+
+// Instance data of the vertex, either pulled from the instance storage buffer or taken from the
+// instance vertex attributes
+fn instanced_sprite_instance(vertex: Vertex) -> SpriteInstance {
+    var instance: SpriteInstance;
+#ifdef STORAGE_INSTANCES
+    let data = instances[vertex.instance_index];
+    instance.position = vec3(data.position[0], data.position[1], data.position[2]);
+    instance.rotation_scale = vec4(
+        data.rotation_scale[0],
+        data.rotation_scale[1],
+        data.rotation_scale[2],
+        data.rotation_scale[3],
+    );
+    instance.tex_index = data.tex_index;
+    instance.color = vec4(data.color[0], data.color[1], data.color[2], data.color[3]);
+    instance.animation = vec2(data.animation[0], data.animation[1]);
+    instance.animation_time = vec2(data.animation_time[0], data.animation_time[1]);
+    instance.anchor = vec2(data.anchor[0], data.anchor[1]);
+    instance.flags = data.flags;
+#else
+    instance.position = vertex.i_position;
+    instance.rotation_scale = vertex.i_rotation_scale;
+    instance.tex_index = vertex.i_tex_index;
+    instance.color = vertex.i_color;
+    instance.animation = vertex.i_animation;
+    instance.animation_time = vertex.i_animation_time;
+    instance.anchor = vertex.i_anchor;
+    instance.flags = vertex.i_flags;
+#endif
+    return instance;
+}
 
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
 
     @location(0) m_tex_coords: vec2<f32>,
-    @location(1) m_tex_index: u32
+    @location(1) m_tex_index: u32,
+    @location(2) m_color: vec4<f32>,
 };
 
-@vertex
-fn vertex(vertex: Vertex) -> VertexOutput {
+fn animation_frame(animation: vec2<u32>, animation_time: vec2<f32>) -> u32 {
+    let frame_count = animation.x;
+    if frame_count <= 1u {
+        return 0u;
+    }
+
+    var elapsed = globals.time - animation_time.y;
+    if elapsed < 0.0 {
+        elapsed += instancing_group.time_wrap_period;
+    }
+    let frame = u32(elapsed * animation_time.x);
+
+    switch animation.y {
+        // Once
+        case 1u: {
+            return min(frame, frame_count - 1u);
+        }
+        // Ping-pong
+        case 2u: {
+            let period = 2u * (frame_count - 1u);
+            let step = frame % period;
+            return select(step, period - step, step >= frame_count);
+        }
+        // Loop
+        default: {
+            return frame % frame_count;
+        }
+    }
+}
+
+fn instanced_sprite_vertex(vertex: Vertex) -> VertexOutput {
     var out: VertexOutput;
+    let instance = instanced_sprite_instance(vertex);
+
+    let rotation_scale = mat2x2<f32>(instance.rotation_scale.xy, instance.rotation_scale.zw);
+    let position_2d = rotation_scale * (vertex.v_position - instance.anchor) + instance.position.xy;
+    let position_ws = vec4(position_2d, instance.position.z, 1.0);
 
-    let position_2d = vertex.v_position * vertex.i_scale + vertex.i_position.xy;
-    let position_ws = vec4(position_2d, vertex.i_position.z, 1.0);
+    var tex_coords = vertex.v_tex_coords;
+    if (instance.flags & 1u) != 0u {
+        tex_coords.x = 1.0 - tex_coords.x;
+    }
+    if (instance.flags & 2u) != 0u {
+        tex_coords.y = 1.0 - tex_coords.y;
+    }
 
-    out.clip_position = mesh2d_position_world_to_clip(position_ws);
-    out.m_tex_coords = vertex.v_tex_coords;
-    out.m_tex_index = vertex.i_tex_index;
+    out.clip_position = view.view_proj * position_ws;
+    out.m_tex_coords = tex_coords;
+    out.m_tex_index = instance.tex_index + animation_frame(instance.animation, instance.animation_time);
+    out.m_color = instance.color;
 
     return out;
 }
 
+// Texture coordinates of the instance's tile within the spritesheet (or its texture array layer)
+fn instanced_sprite_tex_coords(in: VertexOutput) -> vec2<f32> {
+#ifdef SPRITESHEET_TEXTURE_ARRAY
+    return in.m_tex_coords;
+#else
+    if spritesheet.rect_count > 0u {
+        let rect = spritesheet_rects[min(in.m_tex_index, spritesheet.rect_count - 1u)];
+        let texture_size = vec2<f32>(textureDimensions(texture));
+        return mix(rect.xy, rect.zw, in.m_tex_coords) / texture_size;
+    }
+
+    let size_tiles = spritesheet.size_tiles;
+    let size_tiles_f32 = vec2(f32(size_tiles.x), f32(size_tiles.y));
+
+    let t_u = f32(in.m_tex_index % size_tiles.x) / size_tiles_f32.x;
+    let t_v = f32(in.m_tex_index / size_tiles.x) / size_tiles_f32.y;
+    return in.m_tex_coords / size_tiles_f32 + vec2(t_u, t_v);
+#endif
+}
+
+// Spritesheet texel multiplied by the instance color
+fn instanced_sprite_color(in: VertexOutput) -> vec4<f32> {
+    let tex_coords = instanced_sprite_tex_coords(in);
+#ifdef SPRITESHEET_TEXTURE_ARRAY
+    return textureSample(texture, texture_sampler, tex_coords, i32(in.m_tex_index)) * in.m_color;
+#else
+    return textureSample(texture, texture_sampler, tex_coords) * in.m_color;
+#endif
+}
+
+// Applies the group's blend mode to the final color
+fn instanced_sprite_blend(color: vec4<f32>) -> vec4<f32> {
+#ifdef BLEND_OPAQUE
+    return vec4(color.rgb, 1.0);
+#else ifdef BLEND_ALPHA_MASK
+    if color.a < instancing_group.alpha_cutoff {
+        discard;
+    }
+    return vec4(color.rgb, 1.0);
+#else ifdef BLEND_MULTIPLY
+    return vec4(mix(vec3(1.0), color.rgb, color.a), color.a);
+#else
+    return color;
+#endif
+}
+"#;
+
+pub(super) const INSTANCED_ENTITY_SHADER: &str = r#"
+#import bevy_sprite_instancing::instanced_sprite
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    return instanced_sprite_vertex(vertex);
+}
+
 @fragment
 fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
-    let tile_size_f32 = vec2(f32(spritesheet_tile_size.x), f32(spritesheet_tile_size.y));
+    return instanced_sprite_blend(instanced_sprite_color(in));
+}
+"#;
+
+pub const INSTANCE_CULLING_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 12344143414124);
+
+pub(super) const INSTANCE_CULLING_SHADER: &str = r#"
+#import bevy_sprite_instancing::instance_data
+
+struct CullingParams {
+    // Bounds of the group's mesh in sprite space
+    mesh_center: vec2<f32>,
+    mesh_half_size: vec2<f32>,
+    instance_count: u32,
+    // Instances aren't culled when there are no views
+    view_count: u32,
+    // Min and max corners of each view rectangle
+    views: array<vec4<f32>, 8>,
+};
+
+// Laid out for both `draw_indirect` and `draw_indexed_indirect`, the trailing zeros are the
+// first vertex/index, base vertex and first instance
+struct DrawIndirectArgs {
+    vertex_count: u32,
+    instance_count: atomic<u32>,
+    first_vertex: u32,
+    base_vertex: u32,
+    first_instance: u32,
+};
+
+@group(0) @binding(0)
+var<uniform> params: CullingParams;
+@group(0) @binding(1)
+var<storage> instances: array<SpriteInstanceData>;
+@group(0) @binding(2)
+var<storage, read_write> culled_instances: array<SpriteInstanceData>;
+@group(0) @binding(3)
+var<storage, read_write> draw_args: DrawIndirectArgs;
+
+fn is_instance_visible(instance: SpriteInstanceData) -> bool {
+    if params.view_count == 0u {
+        return true;
+    }
+
+    let rs = instance.rotation_scale;
+    let rotation_scale = mat2x2<f32>(vec2(rs[0], rs[1]), vec2(rs[2], rs[3]));
+    let position = vec2(instance.position[0], instance.position[1]);
+    let anchor = vec2(instance.anchor[0], instance.anchor[1]);
+
+    let center = position + rotation_scale * (params.mesh_center - anchor);
+    let half_size = abs(rotation_scale[0]) * params.mesh_half_size.x
+        + abs(rotation_scale[1]) * params.mesh_half_size.y;
+    let bounds_min = center - half_size;
+    let bounds_max = center + half_size;
+
+    for (var i = 0u; i < params.view_count; i += 1u) {
+        let view = params.views[i];
+        if all(bounds_min < view.zw) && all(bounds_max > view.xy) {
+            return true;
+        }
+    }
+    return false;
+}
 
-    let t_u = f32(in.m_tex_index % spritesheet_tile_size.x) / tile_size_f32.x;
-    let t_v = f32(in.m_tex_index / spritesheet_tile_size.x) / tile_size_f32.y;
-    let tex_coords = in.m_tex_coords / tile_size_f32 + vec2(t_u, t_v);
+@compute @workgroup_size(64)
+fn cull(
+    @builtin(global_invocation_id) invocation_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>,
+) {
+    // Workgroups are laid out in rows when there are too many for a single dimension
+    let index = invocation_id.y * num_workgroups.x * 64u + invocation_id.x;
+    if index >= params.instance_count {
+        return;
+    }
 
-    let tex_color = textureSample(texture, texture_sampler, tex_coords);
-    return tex_color;
+    let instance = instances[index];
+    if is_instance_visible(instance) {
+        let slot = atomicAdd(&draw_args.instance_count, 1u);
+        culled_instances[slot] = instance;
+    }
 }
 "#;